@@ -16,6 +16,18 @@ fn main() {
              .takes_value(true)
              .value_name("PORT")
              .help("TCP port to listen (default 4400)"))
+        .arg(clap::Arg::with_name("bind")
+             .short("b")
+             .long("bind")
+             .takes_value(true)
+             .value_name("HOST")
+             .help("address to listen (default 0.0.0.0)"))
+        .arg(clap::Arg::with_name("unixsocket")
+             .short("s")
+             .long("unixsocket")
+             .takes_value(true)
+             .value_name("PATH")
+             .help("Unix domain socket to listen in addition to TCP"))
         .arg(clap::Arg::with_name("verbose")
              .short("v")
              .long("verbose")
@@ -24,6 +36,7 @@ fn main() {
         .get_matches();
 
     let port  = args.value_of("port").unwrap_or("4400").parse::<u16>().unwrap_or(4400);
+    let host  = args.value_of("bind").unwrap_or("0.0.0.0");
     //let debug = false;
     //let verbose = args.is_present("verbose");
 
@@ -32,7 +45,13 @@ fn main() {
     let store = Store::new();
     let handler = Arc::new(Mutex::new(store));
 
-    if let Err(ref e) = redif::run( port, handler.clone() ) {
+    let mut builder = redif::Server::builder().bind(format!("{}:{}", host, port));
+    if let Some(path) = args.value_of("unixsocket") {
+        builder = builder.bind_unix(path).unix_socket_perm(0o700);
+    }
+    let server = builder.build(handler.clone());
+
+    if let Err(ref e) = server.and_then(|server| server.serve()) {
         error!("ERROR {}", e);
         std::process::exit(1);
     }
@@ -61,7 +80,7 @@ impl Store {
         }
 
         let key = match &args[0] {
-            &Value::Data(ref data) => {
+            Value::Data(data) => {
                 match ::std::str::from_utf8(data) {
                     Ok(s) => s.to_owned(),
                     Err(e) => {
@@ -75,7 +94,7 @@ impl Store {
             }
         };
         let val = match &args[1] {
-            &Value::Data(ref data) => {
+            Value::Data(data) => {
                 match ::std::str::from_utf8(data) {
                     Ok(s) => s.to_owned(),
                     Err(e) => {
@@ -95,12 +114,12 @@ impl Store {
     }
 
     fn command_get(&mut self, args: &[Value]) -> Option<Value> {
-        if args.len() < 1 {
+        if args.is_empty() {
             return Some(Value::Error("too few arguments".to_owned()))
         }
 
         let key = match &args[0] {
-            &Value::Data(ref data) => {
+            Value::Data(data) => {
                 match ::std::str::from_utf8(data) {
                     Ok(s) => s.to_owned(),
                     Err(e) => {
@@ -121,7 +140,7 @@ impl Store {
     }
 
     fn command_ping(&self, args: &[Value]) -> Option<Value> {
-        if args.len() > 0 {
+        if !args.is_empty() {
             let v = args.iter().map(|x| x.as_slice()).collect::<Vec<&[u8]>>().join(&0x20u8);
             Some(Value::Data(v))
         } else {
@@ -134,7 +153,7 @@ impl Handler for Store {
 
     fn handle(&mut self, data: &Value) -> Option<Value> {
         if let Value::Bulk(ref req) = *data {
-            if req.len() < 1 {
+            if req.is_empty() {
                 return Some(Value::Nil);
             }
 
@@ -178,7 +197,7 @@ fn init_logging() {
     use env_logger::LogBuilder;
     use std::env;
 
-    static LOG_LEVEL_NAMES: [&'static str; 6] = ["O", "E", "W", "I", "D", "T"];
+    static LOG_LEVEL_NAMES: [&str; 6] = ["O", "E", "W", "I", "D", "T"];
 
     fn basename(filename: &str) -> &str {
         filename.split("/").last().unwrap()
@@ -0,0 +1,211 @@
+//! Per-connection state handed to the `Handler`
+//!
+
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::time::{Duration, Instant, SystemTime};
+
+use error::Error;
+use listener::SockAddr;
+use reply::{ReplySender, Token};
+use value::Protocol;
+
+/// What a handler knows about the client sending a request.
+///
+/// One context lives as long as its connection, so what the handler sets in it,
+/// like the selected db or the client name, is kept across the requests.
+pub struct ConnContext {
+    id: usize,
+    peer_addr: SockAddr,
+    local_addr: SockAddr,
+    listener: usize,
+    connected_at: SystemTime,
+    pub(crate) last_active: Instant,
+    pub(crate) killed: bool,
+    pub(crate) protocol: Protocol,
+    pub(crate) class: ClientClass,
+    db: usize,
+    name: Option<String>,
+    user_data: Option<Box<dyn Any + Send>>,
+    replies: ReplySender,
+    next_token: u64,
+}
+
+impl ConnContext {
+    pub(crate) fn new(id: usize, peer_addr: SockAddr, local_addr: SockAddr, listener: usize, replies: ReplySender) -> ConnContext {
+        ConnContext {
+            id,
+            peer_addr,
+            local_addr,
+            listener,
+            connected_at: SystemTime::now(),
+            last_active: Instant::now(),
+            killed: false,
+            protocol: Protocol::default(),
+            class: ClientClass::default(),
+            db: 0,
+            name: None,
+            user_data: None,
+            replies,
+            next_token: 0,
+        }
+    }
+
+    /// Client id, unique among the connected clients.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Address of the client.
+    pub fn peer_addr(&self) -> &SockAddr {
+        &self.peer_addr
+    }
+
+    /// Address the client is connected to.
+    pub fn local_addr(&self) -> &SockAddr {
+        &self.local_addr
+    }
+
+    /// Index of the listener which accepted the connection, in the order of `bind()` calls.
+    pub fn listener(&self) -> usize {
+        self.listener
+    }
+
+    pub fn connected_at(&self) -> SystemTime {
+        self.connected_at
+    }
+
+    /// Time elapsed since the last request of the client, or since it connected.
+    pub fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+
+    /// Close the connection once the replies to the requests read so far are sent,
+    /// e.g. on `QUIT` or `CLIENT KILL`.
+    pub fn kill(&mut self) {
+        self.killed = true;
+    }
+
+    /// Token to return as `Reply::Deferred` from the handler, and to `send()` the reply with later.
+    pub fn defer(&mut self) -> Token {
+        self.next_token += 1;
+        Token::new(self.id, self.next_token)
+    }
+
+    /// Sender of the deferred replies, to move to the thread making them.
+    pub fn reply_sender(&self) -> ReplySender {
+        self.replies.clone()
+    }
+
+    /// Protocol version negotiated by `HELLO`.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    /// Class of the client for its output buffer limit, `Normal` until `set_class()`.
+    pub fn class(&self) -> ClientClass {
+        self.class
+    }
+
+    /// I.e. `Pubsub` once the client subscribes, as its replies may pile up faster.
+    pub fn set_class(&mut self, class: ClientClass) {
+        self.class = class;
+    }
+
+    /// Database selected, 0 until `set_db()`.
+    pub fn db(&self) -> usize {
+        self.db
+    }
+
+    pub fn set_db(&mut self, db: usize) {
+        self.db = db;
+    }
+
+    /// Name given by the client, e.g. with `CLIENT SETNAME`.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn set_name(&mut self, name: Option<String>) {
+        self.name = name;
+    }
+
+    /// The user data, if it is a `T`.
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.user_data.as_ref().and_then(|data| data.downcast_ref())
+    }
+
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.user_data.as_mut().and_then(|data| data.downcast_mut())
+    }
+
+    /// Attach any data to the connection, e.g. the authenticated user, replacing the previous one.
+    pub fn set_user_data<T: Any + Send>(&mut self, data: T) {
+        self.user_data = Some(Box::new(data));
+    }
+
+    pub fn take_user_data(&mut self) -> Option<Box<dyn Any + Send>> {
+        self.user_data.take()
+    }
+}
+
+impl fmt::Debug for ConnContext {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ConnContext")
+            .field("id", &self.id)
+            .field("peer_addr", &self.peer_addr)
+            .field("local_addr", &self.local_addr)
+            .field("listener", &self.listener)
+            .field("connected_at", &self.connected_at)
+            .field("protocol", &self.protocol)
+            .field("class", &self.class)
+            .field("db", &self.db)
+            .field("name", &self.name)
+            .field("killed", &self.killed)
+            .field("user_data", &self.user_data.is_some())
+            .finish()
+    }
+}
+
+/// Kind of client, each with its own output buffer limit like Redis `client-output-buffer-limit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ClientClass {
+    #[default]
+    Normal,
+    Replica,
+    Pubsub,
+}
+
+/// Why a client is disconnected
+#[derive(Debug)]
+pub enum DisconnectReason {
+    /// the client closed the connection
+    Eof,
+    /// the client sent a malformed request
+    Protocol(Error),
+    /// reading from or writing to the client failed
+    Io(io::Error),
+    /// the client was idle longer than the `idle_timeout` of the server
+    Timeout,
+    /// the handler called `ConnContext::kill()`
+    Killed,
+    /// the replies pending for the client went over its output buffer limit
+    OutputBufferLimit,
+    /// the server is shutting down
+    Shutdown,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisconnectReason::Eof => write!(fmt, "closed"),
+            DisconnectReason::Protocol(ref e) => write!(fmt, "Protocol error: {}", e),
+            DisconnectReason::Io(ref e) => write!(fmt, "{}", e),
+            DisconnectReason::Timeout => write!(fmt, "idle timeout"),
+            DisconnectReason::Killed => write!(fmt, "killed"),
+            DisconnectReason::OutputBufferLimit => write!(fmt, "output buffer limit reached"),
+            DisconnectReason::Shutdown => write!(fmt, "shutdown"),
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! Resumable decoder of RESP values
+//!
+//! The decoder keeps the partial progress of a frame (the arrays being filled, the bytes of a bulk
+//! string still expected) across reads, so every byte received is looked at only once.
+
+use std::cmp;
+use std::mem;
+use std::ops::{Deref, Range};
+use std::str;
+
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+
+use error::{Error, Result};
+use value::Value;
+
+const CRLF_BYTES: &[u8] = b"\r\n";
+
+/// First bytes of the RESP types, any other starts an inline command
+const RESP_PREFIXES: &[u8] = b"+-:$*_,#(!=%~|>";
+
+/// Upper bound of the elements preallocated for an array, whatever length the client announces.
+const MAX_ARRAY_PREALLOC: usize = 1024;
+
+/// Upper bound of the bytes preallocated for a bulk string, which grows as its bytes arrive.
+const MAX_BULK_PREALLOC: usize = 64 * 1024;
+
+/// Bytes to decode, which decide how bulk strings are represented.
+pub trait Source: Deref<Target = [u8]> {
+    /// Whether a bulk string must be passed at once rather than gathered from several reads.
+    const CONTIGUOUS: bool = false;
+
+    /// A bulk string lying in `range` of these bytes.
+    fn data(&self, range: Range<usize>) -> Value;
+}
+
+/// Bulk strings are copied into `Value::Data`, gathered from several reads if need be.
+impl Source for &[u8] {
+    fn data(&self, range: Range<usize>) -> Value {
+        Value::Data(self[range].to_vec())
+    }
+}
+
+/// Bulk strings are `Value::Shared` slices of the bytes, which must hold each of them whole.
+#[cfg(feature = "bytes")]
+impl Source for Bytes {
+    const CONTIGUOUS: bool = true;
+
+    fn data(&self, range: Range<usize>) -> Value {
+        Value::Shared(self.slice(range))
+    }
+}
+
+#[derive(Debug)]
+pub struct Decoder {
+    max_frame_size: usize,
+    /// bytes consumed by the frame in progress
+    frame_len: usize,
+    /// aggregates being filled: type, elements, and the number of elements expected
+    arrays: Vec<(u8, Vec<Value>, usize)>,
+    /// payload and CRLF bytes still expected by the bulk string in progress
+    bulk_remaining: Option<usize>,
+    /// type of the bulk string in progress, `$`, `!` or `=`
+    bulk_kind: u8,
+    bulk: Vec<u8>,
+    /// whether a line without a RESP type is an inline command, like `PING\r\n`
+    inline: bool,
+}
+
+impl Decoder {
+    pub fn new(max_frame_size: usize) -> Decoder {
+        Decoder {
+            max_frame_size,
+            frame_len: 0,
+            arrays: Vec::new(),
+            bulk_remaining: None,
+            bulk_kind: b'$',
+            bulk: Vec::new(),
+            inline: false,
+        }
+    }
+
+    /// A decoder of client requests, which may be inline commands as typed in `telnet` or `nc`.
+    pub fn requests(max_frame_size: usize) -> Decoder {
+        Decoder {
+            inline: true,
+            ..Decoder::new(max_frame_size)
+        }
+    }
+
+    /// Bytes of the bulk string in progress, which a contiguous source has to hold at once.
+    #[cfg(feature = "bytes")]
+    pub fn pending_bulk(&self) -> Option<usize> {
+        self.bulk_remaining
+    }
+
+    /// Decode the next value out of `bytes`.
+    ///
+    /// Returns the value, if `bytes` completes one, and the number of bytes consumed. The
+    /// consumed bytes are not needed anymore, even if no value is complete yet; the bytes left
+    /// must be passed again with the following ones.
+    pub fn decode<S: Source>(&mut self, bytes: S) -> Result<(Option<Value>, usize)> {
+        let mut pos = 0;
+        loop {
+            let value = match self.bulk_remaining {
+                Some(remaining) if self.bulk.is_empty() && bytes.len() - pos >= remaining => {
+                    // all of it at hand
+                    self.bulk_remaining = None;
+                    let n = remaining - CRLF_BYTES.len();
+                    if &bytes[pos + n .. pos + remaining] != CRLF_BYTES {
+                        return Err(Error::InvalidCrlf);
+                    }
+                    let value = bytes.data(pos .. pos + n);
+                    pos += self.consume(remaining)?;
+                    blob(self.bulk_kind, value)?
+                }
+                Some(_) if S::CONTIGUOUS => return Ok((None, pos)),
+                Some(remaining) => {
+                    let n = cmp::min(remaining, bytes.len() - pos);
+                    if self.bulk.capacity() == 0 {
+                        self.bulk.reserve_exact(cmp::min(remaining, MAX_BULK_PREALLOC));
+                    }
+                    self.bulk.extend_from_slice(&bytes[pos .. pos + n]);
+                    pos += self.consume(n)?;
+                    if n < remaining {
+                        self.bulk_remaining = Some(remaining - n);
+                        return Ok((None, pos));
+                    }
+                    self.bulk_remaining = None;
+
+                    let mut data = mem::take(&mut self.bulk);
+                    let len = data.len() - CRLF_BYTES.len();
+                    if &data[len ..] != CRLF_BYTES {
+                        return Err(Error::InvalidCrlf);
+                    }
+                    data.truncate(len);
+                    blob(self.bulk_kind, Value::Data(data))?
+                }
+                None => {
+                    let k = match bytes[pos ..].iter().position(|&b| b == b'\n') {
+                        Some(k) => pos + k,
+                        None => return Ok((None, pos)),
+                    };
+                    if self.inline && self.arrays.is_empty() && !RESP_PREFIXES.contains(&bytes[pos]) {
+                        // the CR is optional, as with Redis
+                        let line = &bytes[pos .. k];
+                        let line = line.strip_suffix(b"\r").unwrap_or(line);
+                        pos += self.consume(k + 1 - pos)?;
+                        let args = split_args(line).ok_or(Error::UnbalancedQuotes)?;
+                        self.frame_len = 0;
+                        if args.is_empty() {
+                            continue;
+                        }
+                        return Ok((Some(Value::Bulk(args.into_iter().map(Value::Data).collect())), pos));
+                    }
+                    if k == pos || bytes[k - 1] != b'\r' {
+                        return Err(Error::InvalidCrlf);
+                    }
+                    let prefix = bytes[pos];
+                    let line = &bytes[pos + 1 .. k - 1];
+                    pos += self.consume(k + 1 - pos)?;
+
+                    match prefix {
+                        b'+' => Value::Status(str::from_utf8(line)?.to_owned()),
+                        b'-' => Value::Error(str::from_utf8(line)?.to_owned()),
+                        b':' => Value::Int(parse_number(line).ok_or(Error::InvalidInteger)?),
+                        b'_' if line.is_empty() => Value::Null,
+                        b'#' if line == b"t" => Value::Boolean(true),
+                        b'#' if line == b"f" => Value::Boolean(false),
+                        b',' => Value::Double(parse_double(line).ok_or(Error::InvalidValue(prefix))?),
+                        b'(' => Value::BigNumber(parse_big_number(line).ok_or(Error::InvalidValue(prefix))?),
+                        b'_' | b'#' => return Err(Error::InvalidValue(prefix)),
+                        b'$' | b'!' | b'=' => match self.parse_length(line, prefix)? {
+                            None if prefix == b'$' => Value::Nil,
+                            None => return Err(Error::InvalidLength(prefix)),
+                            Some(n) => {
+                                self.bulk_kind = prefix;
+                                self.bulk_remaining = Some(n + CRLF_BYTES.len());
+                                continue;
+                            }
+                        },
+                        b'*' | b'%' | b'~' | b'|' | b'>' => match self.parse_length(line, prefix)? {
+                            None if prefix == b'*' => Value::NullArray,
+                            None => return Err(Error::InvalidLength(prefix)),
+                            Some(n) => {
+                                // a map holds keys and values, attributes are followed by the value
+                                let len = match prefix {
+                                    b'%' => 2 * n,
+                                    b'|' => 2 * n + 1,
+                                    _ => n,
+                                };
+                                if len == 0 {
+                                    aggregate(prefix, Vec::new())
+                                } else {
+                                    self.arrays.push((prefix, Vec::with_capacity(cmp::min(len, MAX_ARRAY_PREALLOC)), len));
+                                    continue;
+                                }
+                            }
+                        },
+                        prefix => return Err(Error::InvalidPrefix(prefix)),
+                    }
+                }
+            };
+
+            if let Some(value) = self.complete(value) {
+                self.frame_len = 0;
+                return Ok((Some(value), pos));
+            }
+        }
+    }
+
+    /// Add a value to the aggregates in progress, returns the frame once the outermost is full.
+    fn complete(&mut self, mut value: Value) -> Option<Value> {
+        while let Some((prefix, mut items, len)) = self.arrays.pop() {
+            items.push(value);
+            if items.len() < len {
+                self.arrays.push((prefix, items, len));
+                return None;
+            }
+            value = aggregate(prefix, items);
+        }
+        Some(value)
+    }
+
+    /// Account `n` more bytes to the frame in progress.
+    fn consume(&mut self, n: usize) -> Result<usize> {
+        self.frame_len += n;
+        if self.frame_len > self.max_frame_size {
+            return Err(Error::FrameTooLarge);
+        }
+        Ok(n)
+    }
+
+    /// The length of a bulk string or an array, `None` for a null one.
+    fn parse_length(&self, line: &[u8], prefix: u8) -> Result<Option<usize>> {
+        let x = parse_number(line).ok_or(Error::InvalidLength(prefix))?;
+        if x == -1 {
+            return Ok(None);
+        }
+        if x < -1 {
+            return Err(Error::InvalidLength(prefix));
+        }
+        // every element of an array takes a few bytes at least
+        if x as u64 > (self.max_frame_size - self.frame_len) as u64 {
+            return Err(Error::FrameTooLarge);
+        }
+        Ok(Some(x as usize))
+    }
+}
+
+/// A bulk string of type `prefix` out of its payload.
+fn blob(prefix: u8, data: Value) -> Result<Value> {
+    let into_vec = |data: Value| match data {
+        Value::Data(data) => data,
+        data => data.as_slice().to_vec(),
+    };
+    match prefix {
+        b'!' => Ok(Value::BlobError(into_vec(data))),
+        b'=' => {
+            let mut data = into_vec(data);
+            if data.len() < 4 || data[3] != b':' {
+                return Err(Error::InvalidValue(prefix));
+            }
+            let format = str::from_utf8(&data[.. 3])?.to_owned();
+            data.drain(.. 4);
+            Ok(Value::Verbatim(format, data))
+        }
+        _ => Ok(data),
+    }
+}
+
+/// An aggregate of type `prefix` out of its elements.
+fn aggregate(prefix: u8, items: Vec<Value>) -> Value {
+    let pairs = |items: Vec<Value>| {
+        let mut pairs = Vec::with_capacity(items.len() / 2);
+        let mut items = items.into_iter();
+        while let (Some(key), Some(val)) = (items.next(), items.next()) {
+            pairs.push((key, val));
+        }
+        pairs
+    };
+    match prefix {
+        b'%' => Value::Map(pairs(items)),
+        b'~' => Value::Set(items),
+        b'>' => Value::Push(items),
+        b'|' => {
+            let mut items = items;
+            let val = items.pop().expect("attributes are followed by a value");
+            Value::Attribute(pairs(items), Box::new(val))
+        }
+        _ => Value::Bulk(items),
+    }
+}
+
+/// Split an inline command into arguments like redis-cli does.
+///
+/// Arguments are separated by spaces, and may be quoted: double quotes support the `\n \r \t \b \a`
+/// and `\xHH` escapes, single quotes the `\'` one. `None` if a quote is not closed, or is followed
+/// by anything but a space.
+fn split_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let len = line.len();
+    let is_hex = |b: u8| b.is_ascii_hexdigit();
+    let hex = |b: u8| (b as char).to_digit(16).unwrap_or(0) as u8;
+
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < len && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == len {
+            return Some(args);
+        }
+
+        let mut arg = Vec::new();
+        let mut in_quotes = false;
+        let mut in_single_quotes = false;
+        loop {
+            if in_quotes {
+                if i == len {
+                    return None;
+                }
+                if line[i] == b'\\' && i + 3 < len && line[i + 1] == b'x' && is_hex(line[i + 2]) && is_hex(line[i + 3]) {
+                    arg.push(hex(line[i + 2]) * 16 + hex(line[i + 3]));
+                    i += 3;
+                } else if line[i] == b'\\' && i + 1 < len {
+                    i += 1;
+                    arg.push(match line[i] {
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'b' => 0x08,
+                        b'a' => 0x07,
+                        c => c,
+                    });
+                } else if line[i] == b'"' {
+                    if i + 1 < len && !line[i + 1].is_ascii_whitespace() {
+                        return None;
+                    }
+                    i += 1;
+                    break;
+                } else {
+                    arg.push(line[i]);
+                }
+            } else if in_single_quotes {
+                if i == len {
+                    return None;
+                }
+                if line[i] == b'\\' && i + 1 < len && line[i + 1] == b'\'' {
+                    i += 1;
+                    arg.push(b'\'');
+                } else if line[i] == b'\'' {
+                    if i + 1 < len && !line[i + 1].is_ascii_whitespace() {
+                        return None;
+                    }
+                    i += 1;
+                    break;
+                } else {
+                    arg.push(line[i]);
+                }
+            } else {
+                if i == len || line[i].is_ascii_whitespace() {
+                    break;
+                }
+                match line[i] {
+                    b'"' => in_quotes = true,
+                    b'\'' => in_single_quotes = true,
+                    c => arg.push(c),
+                }
+            }
+            i += 1;
+        }
+        args.push(arg);
+    }
+}
+
+/// `inf`, `-inf`, `nan` or a decimal number with an optional exponent
+fn parse_double(bytes: &[u8]) -> Option<f64> {
+    let s = str::from_utf8(bytes).ok()?;
+    match s {
+        "inf" => Some(f64::INFINITY),
+        "-inf" => Some(f64::NEG_INFINITY),
+        "nan" => Some(f64::NAN),
+        _ if s.bytes().all(|b| b.is_ascii_digit() || b"+-.eE".contains(&b)) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn parse_big_number(bytes: &[u8]) -> Option<String> {
+    let digits = if bytes.first() == Some(&b'-') { &bytes[1 ..] } else { bytes };
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    Some(str::from_utf8(bytes).ok()?.to_owned())
+}
+
+fn parse_number(bytes: &[u8]) -> Option<i64> {
+    str::from_utf8(bytes).ok()?.parse::<i64>().ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Decoder, MAX_BULK_PREALLOC};
+    use super::super::error::Error;
+    use super::super::value::Value;
+
+    #[test]
+    fn resume_across_chunks() {
+        let data = b"*3\r\n$3\r\nSET\r\n*1\r\n:7\r\n$10\r\n0123456789\r\n+OK\r\n";
+        let want = Value::Bulk(vec![
+            Value::Data(b"SET".to_vec()),
+            Value::Bulk(vec![Value::Int(7)]),
+            Value::Data(b"0123456789".to_vec()),
+        ]);
+
+        for chunk_size in 1 .. data.len() + 1 {
+            let mut decoder = Decoder::new(1024);
+            let mut pending: Vec<u8> = Vec::new();
+            let mut values = Vec::new();
+            for chunk in data.chunks(chunk_size) {
+                pending.extend_from_slice(chunk);
+                loop {
+                    let (value, consumed) = decoder.decode(&pending[..]).unwrap();
+                    pending.drain(.. consumed);
+                    match value {
+                        Some(value) => values.push(value),
+                        None => break,
+                    }
+                }
+                // a bulk string never waits in the pending bytes
+                assert!(pending.len() < 6, "{:?}", pending);
+            }
+            assert_eq!(vec![want.clone(), Value::Status("OK".to_owned())], values);
+            assert_eq!(0, decoder.frame_len);
+        }
+    }
+
+    #[test]
+    fn limit_frame_size() {
+        let mut decoder = Decoder::new(16);
+        match decoder.decode(&b"$17\r\n"[..]) {
+            Err(Error::FrameTooLarge) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+
+        let mut decoder = Decoder::new(16);
+        assert_eq!((None, 10), decoder.decode(&b"*2\r\n$2\r\nab"[..]).unwrap());
+        assert_eq!(10, decoder.frame_len);
+        // the limit applies to the whole array, not to each of its elements
+        match decoder.decode(&b"\r\n+0123456\r\n"[..]) {
+            Err(Error::FrameTooLarge) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn grow_bulk_strings_as_they_arrive() {
+        let mut decoder = Decoder::new(1 << 30);
+        // not the announced length, whatever the client claims
+        assert_eq!((None, 14), decoder.decode(&b"$536870000\r\nab"[..]).unwrap());
+        assert!(decoder.bulk.capacity() <= MAX_BULK_PREALLOC, "{}", decoder.bulk.capacity());
+
+        let mut decoder = Decoder::new(1 << 20);
+        let data = vec![b'x'; 100 * 1024];
+        let mut frame = format!("${}\r\n", data.len()).into_bytes();
+        frame.extend_from_slice(&data[.. 50 * 1024]);
+        assert_eq!((None, frame.len()), decoder.decode(&frame[..]).unwrap());
+        let mut rest = data[50 * 1024 ..].to_vec();
+        rest.extend_from_slice(b"\r\n");
+        assert_eq!((Some(Value::Data(data)), rest.len()), decoder.decode(&rest[..]).unwrap());
+    }
+
+    #[test]
+    fn inline_commands() {
+        let mut decoder = Decoder::requests(1024);
+        let data = b"PING\r\n\r\n  set a  \"b \\\"c\\x41\\n\" 'it\\'s' \"\"\nGET\r\n";
+        let mut values = Vec::new();
+        let mut pending = &data[..];
+        while let (Some(value), consumed) = decoder.decode(pending).unwrap() {
+            values.push(value);
+            pending = &pending[consumed..];
+        }
+        assert!(pending.is_empty());
+        let args = |args: &[&[u8]]| Value::Bulk(args.iter().map(|arg| Value::Data(arg.to_vec())).collect());
+        assert_eq!(vec![
+            args(&[b"PING"]),
+            args(&[b"set", b"a", b"b \"cA\n", b"it's", b""]),
+            args(&[b"GET"]),
+        ], values);
+
+        // waits for the end of the line
+        assert_eq!((None, 0), decoder.decode(&b"GET a"[..]).unwrap());
+
+        for data in &[&b"SET a \"b\r\n"[..], b"SET a 'b\r\n", b"SET a \"b\"c\r\n"] {
+            match Decoder::requests(1024).decode(*data) {
+                Err(Error::UnbalancedQuotes) => {}
+                res => panic!("unexpected {:?}", res),
+            }
+        }
+
+        // only requests may be inline
+        match Decoder::new(1024).decode(&b"PING\r\n"[..]) {
+            Err(Error::InvalidPrefix(b'P')) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,89 @@
+//! Errors of the Redis protocol
+//!
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+use std::str;
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Failure to read a RESP value from a client
+#[derive(Debug)]
+pub enum Error {
+    /// The first byte of a frame is not a RESP type.
+    InvalidPrefix(u8),
+    /// A line is not terminated by CRLF.
+    InvalidCrlf,
+    /// The length of a bulk string (`$ ! =`) or of an aggregate (`* % ~ | >`) is not valid.
+    InvalidLength(u8),
+    /// An integer (`:`) is not a number.
+    InvalidInteger,
+    /// A null (`_`), boolean (`#`), double (`,`), big number (`(`) or verbatim string (`=`) is malformed.
+    InvalidValue(u8),
+    /// A quote of an inline command is not closed.
+    UnbalancedQuotes,
+    /// A frame is larger than allowed.
+    FrameTooLarge,
+    /// A status or an error is not UTF-8.
+    Utf8(str::Utf8Error),
+    /// The connection failed.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Whether the client sent malformed data, rather than the connection failed.
+    pub fn is_protocol(&self) -> bool {
+        !matches!(*self, Error::Io(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidPrefix(prefix) => write!(fmt, "invalid RESP type '{}'", (prefix as char).escape_default()),
+            Error::InvalidCrlf => write!(fmt, "expected '\\r\\n'"),
+            Error::InvalidLength(b'*') | Error::InvalidLength(b'%') | Error::InvalidLength(b'~') |
+            Error::InvalidLength(b'|') | Error::InvalidLength(b'>') => write!(fmt, "invalid multibulk length"),
+            Error::InvalidLength(_) => write!(fmt, "invalid bulk length"),
+            Error::InvalidInteger => write!(fmt, "invalid integer"),
+            Error::InvalidValue(prefix) => write!(fmt, "invalid value of RESP type '{}'", (prefix as char).escape_default()),
+            Error::UnbalancedQuotes => write!(fmt, "unbalanced quotes in request"),
+            Error::FrameTooLarge => write!(fmt, "too big request"),
+            Error::Utf8(ref e) => write!(fmt, "invalid UTF-8 string: {}", e),
+            Error::Io(ref e) => write!(fmt, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Utf8(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<str::Utf8Error> for Error {
+    fn from(e: str::Utf8Error) -> Error {
+        Error::Utf8(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
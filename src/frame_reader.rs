@@ -1,29 +1,90 @@
 //! This reader composes frames of bytes for Redis protocol
 //! 
 
-use std::io::{self, Read, Error, ErrorKind};
+use std::cmp;
+use std::io::{self, Read, ErrorKind};
 use std::collections::VecDeque;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "bytes")]
+use bytes::BytesMut;
 
 //use help;
+use decoder::Decoder;
+use error::{Error, Result};
 use value::Value;
 
+/// Size of a read buffer taken from the pool, it doubles as long lines need.
+const INITIAL_BUFFER_SIZE: usize = 16 * 1024;
+/// Number of idle buffers kept by a pool, the others are freed.
+const MAX_POOLED_BUFFERS: usize = 1024;
+
+/// Read buffers shared by the connections of an event loop, a connection holds one only while a
+/// frame is partial.
+#[derive(Debug, Clone, Default)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>
+}
+
+impl BufferPool {
+    pub fn new() -> BufferPool {
+        BufferPool::default()
+    }
+
+    fn get(&self) -> Vec<u8> {
+        match self.buffers.lock().unwrap().pop() {
+            Some(buf) => buf,
+            None => vec![0; INITIAL_BUFFER_SIZE],
+        }
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        if buf.len() > INITIAL_BUFFER_SIZE {
+            buf.truncate(INITIAL_BUFFER_SIZE);
+            buf.shrink_to_fit();
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < MAX_POOLED_BUFFERS {
+            buffers.push(buf);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct FrameReader {
     frames: Frames
 }
 
 impl FrameReader {
-    pub fn new(max_frame_size: u32) -> FrameReader {
+    pub fn new(max_frame_size: u32, pool: BufferPool) -> FrameReader {
+        FrameReader {
+            frames: Frames::new(max_frame_size, pool)
+        }
+    }
+
+    /// A reader decoding bulk strings as `Value::Shared` slices of its read buffer.
+    ///
+    /// Its buffers are not pooled, they live as long as the values sharing them.
+    #[cfg(feature = "bytes")]
+    pub fn shared(max_frame_size: u32) -> FrameReader {
+        let mut frames = Frames::new(max_frame_size, BufferPool::new());
+        frames.shared = Some(BytesMut::new());
         FrameReader {
-            frames: Frames::new(max_frame_size)
+            frames
         }
     }
 
-    pub fn read<T: Read>(&mut self, reader: &mut T) -> io::Result<usize> {
+    pub fn read<T: Read>(&mut self, reader: &mut T) -> Result<usize> {
         self.frames.read(reader)
     }
 
-    pub fn iter_mut(&mut self) -> Iter {
+    /// Whether the peer closed its side, possibly after the bytes of the last `read()`.
+    pub fn is_eof(&self) -> bool {
+        self.frames.eof
+    }
+
+    pub fn iter_mut(&mut self) -> Iter<'_> {
         Iter {
             frames: &mut self.frames
         }
@@ -44,22 +105,32 @@ impl<'a> Iterator for Iter<'a> {
 
 #[derive(Debug)]
 struct Frames {
-    max_frame_size: u32,
+    max_frame_size: usize,
     bytes_read: usize,
+    /// empty while the connection is idle
     current: Vec<u8>,
-    completed_frames: VecDeque<Value>
+    pool: BufferPool,
+    /// read buffer in place of `current` for `Value::Shared` bulk strings
+    #[cfg(feature = "bytes")]
+    shared: Option<BytesMut>,
+    decoder: Decoder,
+    completed_frames: VecDeque<Value>,
+    /// whether a read returned 0 bytes
+    eof: bool,
 }
 
 impl Frames {
-    pub fn new(max_frame_size: u32) -> Frames {
-       let mut buf = Vec::with_capacity(max_frame_size as usize);
-       unsafe { buf.set_len(max_frame_size as usize); }
-
+    pub fn new(max_frame_size: u32, pool: BufferPool) -> Frames {
         Frames {
-            max_frame_size   : max_frame_size,
+            max_frame_size   : max_frame_size as usize,
             bytes_read       : 0,
-            current          : buf,
-            completed_frames : VecDeque::new()
+            current          : Vec::new(),
+            pool,
+            #[cfg(feature = "bytes")]
+            shared           : None,
+            decoder          : Decoder::requests(max_frame_size as usize),
+            completed_frames : VecDeque::new(),
+            eof              : false,
         }
     }
 
@@ -68,21 +139,35 @@ impl Frames {
     /// Will stop reading when 0 bytes are retrieved from the latest call to `do_read` or the error
     /// kind is io::ErrorKind::WouldBlock.
     ///
-    /// Returns an error or the total amount of bytes read.
-    fn read<T: Read>(&mut self, reader: &mut T) -> io::Result<usize> {
+    /// Returns an error or the total amount of bytes read. A frame larger than the maximum
+    /// frame size is `Error::FrameTooLarge`. Reading 0 bytes at once is an `UnexpectedEof`
+    /// error, after some bytes it only sets `eof`: sockets are edge triggered, so the end of
+    /// the stream is not reported again.
+    ///
+    /// The buffer goes back to the pool once no partial frame is left in it.
+    fn read<T: Read>(&mut self, reader: &mut T) -> Result<usize> {
+        let res = self.read_all(reader);
+        if self.bytes_read == 0 && !self.current.is_empty() {
+            self.pool.put(mem::take(&mut self.current));
+        }
+        res
+    }
+
+    fn read_all<T: Read>(&mut self, reader: &mut T) -> Result<usize> {
         let mut total_bytes_read = 0;
         loop {
             match self.do_read(reader) {
                 Ok(0) => {
+                    self.eof = true;
                     if total_bytes_read == 0 {
-                        return Err(Error::new(ErrorKind::UnexpectedEof, "Read 0 bytes"));
+                        return Err(Error::Io(io::Error::new(ErrorKind::UnexpectedEof, "Read 0 bytes")));
                     }
                     return Ok(total_bytes_read);
                 },
                 Ok(bytes_read) => {
                     total_bytes_read += bytes_read;
                 },
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => {
                     return Ok(total_bytes_read)
                 }
                 Err(e) => return Err(e)
@@ -90,7 +175,21 @@ impl Frames {
         }
     }
 
-    fn do_read<T: Read>(&mut self, reader: &mut T) -> io::Result<usize> {
+    fn do_read<T: Read>(&mut self, reader: &mut T) -> Result<usize> {
+        #[cfg(feature = "bytes")]
+        {
+            if self.shared.is_some() {
+                return self.do_read_shared(reader);
+            }
+        }
+
+        if self.current.is_empty() {
+            self.current = self.pool.get();
+        } else if self.bytes_read == self.current.len() {
+            // only a line fills the buffer up, bulk strings are decoded as they arrive
+            let len = cmp::min(self.current.len() * 2, self.max_frame_size);
+            self.current.resize(len, 0);
+        }
         let bytes_read = reader.read(&mut self.current[self.bytes_read..])?;
         self.bytes_read += bytes_read;
 
@@ -101,41 +200,88 @@ impl Frames {
         //    }
         //}
 
-        {
-            // to test if the message is complete
-            let mut offset = 0;
-            loop {
-                let (val, _offset) = Value::decode(&self.current[..self.bytes_read], offset)?;
-                if _offset == 0 {
-                    break;
-                }
-                self.completed_frames.push_back(val);
-                offset = _offset;
+        // consume the complete values, and the beginning of the one in progress
+        let mut offset = 0;
+        loop {
+            let (val, consumed) = self.decoder.decode(&self.current[offset..self.bytes_read])?;
+            offset += consumed;
+            match val {
+                Some(val) => self.completed_frames.push_back(val),
+                None => break,
             }
-            if offset > 0 {
-                // reset buffer
-                let mut k = 0;
-                let mut i = offset;
-                while i < self.bytes_read {
-                    self.current[ k ] = self.current[ i ];
-                    i += 1;
-                    k += 1;
-                }
-                self.bytes_read = k;
+        }
+        if offset > 0 {
+            self.current.copy_within(offset..self.bytes_read, 0);
+            self.bytes_read -= offset;
+        }
+        if self.bytes_read >= self.max_frame_size {
+            return Err(Error::FrameTooLarge);
+        }
+
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl Frames {
+    fn do_read_shared<T: Read>(&mut self, reader: &mut T) -> Result<usize> {
+        let buf = self.shared.as_mut().unwrap();
+        let wanted = self.decoder.pending_bulk().unwrap_or(0);
+
+        // room for the whole bulk string in progress, reclaiming the memory of the values
+        // already dropped, or allocating
+        let size = cmp::max(INITIAL_BUFFER_SIZE, wanted.saturating_sub(buf.len()));
+        buf.reserve(size);
+        let start = buf.len();
+        buf.resize(start + size, 0);
+        let bytes_read = match reader.read(&mut buf[start..]) {
+            Ok(bytes_read) => bytes_read,
+            Err(e) => {
+                buf.truncate(start);
+                return Err(e.into());
             }
+        };
+        buf.truncate(start + bytes_read);
+        if buf.len() < wanted {
+            return Ok(bytes_read);
+        }
+
+        // the values slice the bytes read, the beginning of a line left goes back to the buffer
+        let chunk = buf.split().freeze();
+        let mut offset = 0;
+        loop {
+            let (val, consumed) = self.decoder.decode(chunk.slice(offset..))?;
+            offset += consumed;
+            match val {
+                Some(val) => self.completed_frames.push_back(val),
+                None => break,
+            }
+        }
+        buf.extend_from_slice(&chunk[offset..]);
+        if self.decoder.pending_bulk().is_none() && buf.len() >= self.max_frame_size {
+            return Err(Error::FrameTooLarge);
         }
 
         Ok(bytes_read)
     }
 }
 
+impl Drop for Frames {
+    fn drop(&mut self) {
+        if !self.current.is_empty() {
+            self.pool.put(mem::take(&mut self.current));
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::thread;
     use std::io::Cursor;
-    use std::io::Write;
+    use std::io::{ErrorKind, Write};
     use std::net::{TcpListener, TcpStream};
-    use super::FrameReader;
+    use super::{BufferPool, FrameReader, INITIAL_BUFFER_SIZE};
+    use super::super::error::Error;
     use super::super::value::Value;
 
     #[test]
@@ -143,7 +289,7 @@ mod tests {
         let buf1 = String::from("+Hello World\r\n").into_bytes();
         let buf2 = String::from("-Error\r\n").into_bytes();
 
-        let mut reader = FrameReader::new(64);
+        let mut reader = FrameReader::new(64, BufferPool::new());
 
         // Write a partial value
         let mut data = Cursor::new(&buf1[0..5]);
@@ -171,27 +317,104 @@ mod tests {
         assert_eq!(Value::Error("Error".to_string()), val);
     }
 
-    const IP: &'static str = "127.0.0.1:5003";
+    #[test]
+    fn frame_too_large() {
+        let mut reader = FrameReader::new(16, BufferPool::new());
+
+        // frames are consumed as they complete, so they only need to fit one by one
+        let mut data = Cursor::new(b"+0123456789\r\n+0123456789\r\n".to_vec());
+        assert_eq!(26, reader.read(&mut data).unwrap());
+        assert_eq!(2, reader.iter_mut().count());
+
+        let mut data = Cursor::new(b"$32\r\n0123456789abcdef0123456789abcdef\r\n".to_vec());
+        match reader.read(&mut data) {
+            Err(Error::FrameTooLarge) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn grow_and_release_buffer() {
+        let pool = BufferPool::new();
+        let mut reader = FrameReader::new(1024 * 1024, pool.clone());
+
+        // a long line needs the buffer to grow
+        let line = format!("+{}\r\n", "x".repeat(3 * INITIAL_BUFFER_SIZE));
+        let mut data = Cursor::new(line.as_bytes()[.. line.len() - 2].to_vec());
+        reader.read(&mut data).unwrap();
+        assert!(pool.buffers.lock().unwrap().is_empty());
+        let mut data = Cursor::new(b"\r\n".to_vec());
+        reader.read(&mut data).unwrap();
+        assert_eq!(Some(Value::Status("x".repeat(3 * INITIAL_BUFFER_SIZE))), reader.iter_mut().next());
+
+        // idle again, the buffer is back in the pool at its initial size
+        assert_eq!(vec![INITIAL_BUFFER_SIZE], pool.buffers.lock().unwrap().iter().map(|buf| buf.len()).collect::<Vec<_>>());
+
+        // a bulk string does not need the buffer to grow, a line longer than a frame is rejected
+        let mut reader = FrameReader::new(64 * 1024, pool.clone());
+        let mut data = Cursor::new(Value::Data(vec![b'x'; 60 * 1024]).encode());
+        reader.read(&mut data).unwrap();
+        assert_eq!(Some(Value::Data(vec![b'x'; 60 * 1024])), reader.iter_mut().next());
+        let mut data = Cursor::new(vec![b'+'; 64 * 1024]);
+        match reader.read(&mut data) {
+            Err(Error::FrameTooLarge) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+
+        drop(reader);
+        assert_eq!(1, pool.buffers.lock().unwrap().len());
+    }
+
+    #[test]
+    fn eof_after_data() {
+        let mut reader = FrameReader::new(64, BufferPool::new());
+        assert_eq!(7, reader.read(&mut Cursor::new(b"+PONG\r\n".to_vec())).unwrap());
+        assert!(reader.is_eof());
+        assert_eq!(Some(Value::Status("PONG".to_owned())), reader.iter_mut().next());
+
+        let mut reader = FrameReader::new(64, BufferPool::new());
+        match reader.read(&mut Cursor::new(Vec::new())) {
+            Err(Error::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof => {}
+            res => panic!("unexpected {:?}", res),
+        }
+        assert!(reader.is_eof());
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn shared_data() {
+        use bytes::Bytes;
+
+        let mut reader = FrameReader::shared(1024 * 1024);
+
+        // the large argument spans several reads, yet it is sliced rather than gathered
+        let big = vec![b'x'; 100 * 1024];
+        let mut req = Value::Bulk(vec![Value::Data(b"SET".to_vec()), Value::Data(big.clone())]).encode();
+        req.extend_from_slice(b"+OK\r\n");
+        reader.read(&mut Cursor::new(req)).unwrap();
+
+        let want = Value::Bulk(vec![Value::Shared(Bytes::from_static(b"SET")), Value::Shared(Bytes::from(big))]);
+        assert_eq!(vec![want, Value::Status("OK".to_owned())], reader.iter_mut().collect::<Vec<_>>());
+    }
+
     /// Test that we never get an io error, but instead get Ok(0) when the call to read would block
     #[test]
     fn would_block() {
-        let listener = TcpListener::bind(IP).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
         let h = thread::spawn(move || {
-            for stream in listener.incoming() {
-                if let Ok(mut conn) = stream {
-                    conn.set_nonblocking(true).unwrap();
-                    let mut reader = FrameReader::new(512);
-                    //let result = reader.read(&mut conn);
-                    //assert_matches!(result, Ok(0));
-                    let result = reader.read(&mut conn).unwrap();
-                    assert_eq!(result, 0);
-                    return;
-                }
+            if let Some(mut conn) = listener.incoming().flatten().next() {
+                conn.set_nonblocking(true).unwrap();
+                let mut reader = FrameReader::new(512, BufferPool::new());
+                //let result = reader.read(&mut conn);
+                //assert_matches!(result, Ok(0));
+                let result = reader.read(&mut conn).unwrap();
+                assert_eq!(result, 0);
             }
         });
         // Assign to a variable so the sock isn't dropped early
         // Name it with a preceding underscore so we don't get an unused variable warning
-        let _sock = TcpStream::connect(IP).unwrap();
+        let _sock = TcpStream::connect(addr).unwrap();
         h.join().unwrap();
 
     }
@@ -1,9 +1,11 @@
 
 #![allow(dead_code)]
 
-use std::io::{self, Write};
-use std::collections::LinkedList as List;
-use std::mem;
+use std::io::{self, IoSlice, Write};
+use std::collections::VecDeque;
+
+/// Number of frames sent by one vectored write at most.
+const MAX_IOVECS: usize = 64;
 
 /// Abstraction for writing frame buffered data to non-blocking sockets.
 ///
@@ -14,7 +16,9 @@ pub struct FrameWriter {
     is_writable: bool,
     current: Vec<u8>,
     written: usize,
-    pending: List<Vec<u8>>
+    pending: VecDeque<Vec<u8>>,
+    /// bytes not written yet
+    len: usize,
 }
 
 impl FrameWriter {
@@ -24,7 +28,8 @@ impl FrameWriter {
             is_writable: true,
             current: Vec::new(),
             written: 0,
-            pending: List::new()
+            pending: VecDeque::new(),
+            len: 0,
         }
     }
 
@@ -39,7 +44,7 @@ impl FrameWriter {
     /// results in `OK(false)`.
     pub fn write<T: Write>(&mut self, writer: &mut T, data: Option<Vec<u8>>) -> io::Result<bool> {
         if let Some(frame) = data {
-            if frame.len() > 0 {
+            if !frame.is_empty() {
                 self.append_frame(frame);
             }
         }
@@ -52,6 +57,13 @@ impl FrameWriter {
         self.write_as_much_as_possible(writer)
     }
 
+    /// Queue a frame, to be sent with the others by the next `write()`.
+    pub fn push(&mut self, frame: Vec<u8>) {
+        if !frame.is_empty() {
+            self.append_frame(frame);
+        }
+    }
+
     /// Tell the frame writer that the corresponding writer is writable again.
     pub fn writable(&mut self) {
         self.is_writable = true;
@@ -65,7 +77,13 @@ impl FrameWriter {
         self.is_empty
     }
 
+    /// Number of bytes queued, not written yet.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
     fn append_frame(&mut self, frame: Vec<u8>) {
+        self.len += frame.len();
         if self.is_empty {
             self.current = frame;
             self.is_empty = false;
@@ -74,28 +92,28 @@ impl FrameWriter {
         }
     }
 
+    /// Send the pending frames in batches of vectored writes.
     fn write_as_much_as_possible<T: Write>(&mut self, writer: &mut T) -> io::Result<bool> {
         loop {
-            match writer.write(&self.current[self.written..]) {
+            let res = {
+                let mut iovecs = [IoSlice::new(&[]); MAX_IOVECS];
+                iovecs[0] = IoSlice::new(&self.current[self.written..]);
+                let mut count = 1;
+                for frame in self.pending.iter().take(MAX_IOVECS - 1) {
+                    iovecs[count] = IoSlice::new(frame);
+                    count += 1;
+                }
+                writer.write_vectored(&iovecs[..count])
+            };
+            match res {
                 Ok(0) => {
                     self.is_writable = false;
                     return Ok(false);
                 },
                 Ok(n) => {
-                    self.written += n;
-                    if self.written == self.current.len() {
-                        match self.pending.pop_front() {
-                            None => {
-                                self.written = 0;
-                                self.current = Vec::new();
-                                self.is_empty = true;
-                                return Ok(true);
-                            },
-                            Some(data) => {
-                                self.written = 0;
-                                self.current = data;
-                            }
-                        }
+                    self.advance(n);
+                    if self.is_empty {
+                        return Ok(true);
                     }
                 },
                 Err(e) => {
@@ -109,68 +127,135 @@ impl FrameWriter {
         }
     }
 
+    /// Drop the `n` bytes written from the front of the frames.
+    fn advance(&mut self, mut n: usize) {
+        self.len -= n;
+        while n >= self.current.len() - self.written {
+            n -= self.current.len() - self.written;
+            self.written = 0;
+            match self.pending.pop_front() {
+                None => {
+                    self.current = Vec::new();
+                    self.is_empty = true;
+                    return;
+                },
+                Some(data) => {
+                    self.current = data;
+                }
+            }
+        }
+        self.written += n;
+    }
+
 }
 
 /// Convert a u32 in native order to a 4 byte vec in big endian
 pub fn u32_to_vec(n: u32) -> Vec<u8> {
-    unsafe {
-        let bytes: [u8; 4] = mem::transmute(n.to_be());
-        bytes.to_vec()
-    }
+    n.to_be_bytes().to_vec()
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
-    use super::FrameWriter;
+    use std::io::{self, Cursor, IoSlice, Write};
+    use super::{FrameWriter, MAX_IOVECS};
+
+    /// Takes at most `capacity` bytes in each call, and counts them
+    struct Socket {
+        data: Vec<u8>,
+        capacity: usize,
+        calls: usize,
+    }
+
+    impl Write for Socket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.calls += 1;
+            let before = self.data.len();
+            for buf in bufs {
+                let n = (self.capacity - (self.data.len() - before)).min(buf.len());
+                self.data.extend_from_slice(&buf[..n]);
+            }
+            Ok(self.data.len() - before)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
     #[test]
     fn call_write_on_empty_frame_writer() {
         let mut frame_writer = FrameWriter::new();
         let mut buf = vec![0; 10];
-        assert_eq!(true, frame_writer.write(&mut buf, None).unwrap());
-        assert_eq!(true, frame_writer.is_empty);
+        assert!(frame_writer.write(&mut buf, None).unwrap());
+        assert!(frame_writer.is_empty);
     }
 
     #[test]
     fn call_write_on_empty_frame_writer_but_fill_writer_exactly() {
         let mut frame_writer = FrameWriter::new();
-        let mut buf = vec![0; 10];
+        let mut buf = [0; 10];
         // We use a cursor wrapped around a slice instead of a vec because we want a fixed buffer
         // size. If we used a vec writes would always succeed since the vec would grow.
         let mut writer = Cursor::new(&mut buf[..]);
         let frame = vec![0; 10];
-        assert_eq!(true, frame_writer.write(&mut writer, Some(frame)).unwrap());
-        assert_eq!(true, frame_writer.is_empty);
-        assert_eq!(false, frame_writer.write(&mut writer, Some(vec![0;1])).unwrap());
+        assert!(frame_writer.write(&mut writer, Some(frame)).unwrap());
+        assert!(frame_writer.is_empty);
+        assert!(!frame_writer.write(&mut writer, Some(vec![0;1])).unwrap());
     }
 
     #[test]
     fn write_until_full_reset_and_write_some_more() {
         let mut frame_writer = FrameWriter::new();
-        let mut buf = vec![0; 10];
+        let mut buf = [0; 10];
         let mut writer = Cursor::new(&mut buf[..]);
         let frame = vec![0; 11];
-        assert_eq!(false, frame_writer.write(&mut writer, Some(frame)).unwrap());
-        assert_eq!(false, frame_writer.is_empty);
+        assert!(!frame_writer.write(&mut writer, Some(frame)).unwrap());
+        assert!(!frame_writer.is_empty);
         // At this point there is 1 more byte to be written stored in the frame writer
         assert_eq!(10, frame_writer.written);
-        assert_eq!(true, frame_writer.pending.is_empty());
+        assert!(frame_writer.pending.is_empty());
 
         // Try to write the last byte, but the buffer is full
-        assert_eq!(false, frame_writer.write(&mut writer, None).unwrap());
+        assert!(!frame_writer.write(&mut writer, None).unwrap());
 
         // Make the buffer writable and the buffer size 14 bytes again.
         frame_writer.writable();
         writer.set_position(0);
-        assert_eq!(true, frame_writer.is_writable);
+        assert!(frame_writer.is_writable);
         // Write the last byte remaining, plus a new 9 byte frame and it's 4 byte header.
-        assert_eq!(true, frame_writer.write(&mut writer, Some(vec![0;9])).unwrap());
+        assert!(frame_writer.write(&mut writer, Some(vec![0;9])).unwrap());
         // Ensure that the frame writer was reset because there is no more data to write
-        assert_eq!(true, frame_writer.is_empty);
+        assert!(frame_writer.is_empty);
         assert_eq!(0, frame_writer.written);
         assert_eq!(0, frame_writer.current.len());
     }
-}
 
+    #[test]
+    fn write_pipelined_frames_vectored() {
+        let mut frame_writer = FrameWriter::new();
+        let mut socket = Socket { data: Vec::new(), capacity: 1024 * 1024, calls: 0 };
+        let frames: Vec<Vec<u8>> = (0..100).map(|i| format!("+{}\r\n", i).into_bytes()).collect();
+        for frame in &frames {
+            frame_writer.push(frame.clone());
+        }
+        assert!(frame_writer.write(&mut socket, None).unwrap());
+        assert!(frame_writer.is_empty());
+        assert_eq!(frames.concat(), socket.data);
+        // one call per batch of frames
+        assert_eq!(100_usize.div_ceil(MAX_IOVECS), socket.calls);
 
+        // frames split anywhere by short writes
+        let mut socket = Socket { data: Vec::new(), capacity: 7, calls: 0 };
+        for frame in &frames {
+            frame_writer.push(frame.clone());
+        }
+        assert_eq!(frames.concat().len(), frame_writer.len());
+        assert!(frame_writer.write(&mut socket, None).unwrap());
+        assert_eq!(frames.concat(), socket.data);
+        assert_eq!(0, frame_writer.len());
+    }
+}
@@ -4,11 +4,12 @@
 //!
 //! Redif is a framework, it talks the data transport in redis protocol,
 //! and call user provided Handler to handle the request.
-//! User should implement Handler trait, and invoke Redif with redif::run( port, handler).
+//! User should implement Handler trait, and invoke Redif with redif::run( port, handler),
+//! or with a `Server` configured by `redif::Server::builder()`.
 //! 
 //! For example 
 //! 
-//! ```
+//! ```ignore
 //! 
 //! extern crate redif;
 //! 
@@ -23,8 +24,8 @@
 //! impl Handler for Store {
 //! 
 //!     fn handle(&mut self, data: &Value) -> Option<Value> {
-//! 		/// ...
-//! 	}
+//!         /// ...
+//!     }
 //! 
 //! }
 //! 
@@ -40,6 +41,14 @@
 //!         error!("ERROR {}", e);
 //!         std::process::exit(1);
 //!     }
+//!
+//!     // or tune the server with a builder
+//!     let server = redif::Server::builder()
+//!         .bind("127.0.0.1:4344")
+//!         .max_clients(1024)
+//!         .build(handler.clone())
+//!         .unwrap();
+//!     server.serve().unwrap();
 //! }
 //! 
 //! ```
@@ -49,25 +58,130 @@
 #[macro_use]
 extern crate log;
 extern crate amy;
+extern crate libc;
+#[cfg(feature = "tls")]
+extern crate rustls;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+
+use std::io;
+use std::sync::{Arc, Mutex};
 
 mod redif;
+mod error;
+mod server;
 mod help;
 mod value;
+mod decoder;
 mod frame_reader;
 mod frame_writer;
+mod listener;
+mod context;
+mod reply;
+mod worker;
+#[cfg(feature = "tls")]
+mod tls;
 
+pub use error::Error;
 pub use value::Value;
+pub use value::Protocol;
 pub use value::encode_slice;
-pub use redif::run;
+pub use redif::{run, run_unix};
+pub use listener::SockAddr;
+pub use context::{ClientClass, ConnContext, DisconnectReason};
+pub use reply::{Reply, ReplySender, Token};
+pub use server::{OutputBufferLimit, Server, ServerBuilder, ServerHandle};
 
 /// Handler  handle client's request and produce response
 ///
 /// if there is Some(response), then redif will send response to client;
 /// if there is None response, then redif would send nothing to client,
-/// and client maybe starve!
+/// and client maybe starve! unless `handle_with_ctx()` defers the response.
+///
+/// If a handler panics, its client is disconnected and the handler dropped, without
+/// `on_disconnect()`. A handler shared in an `Arc<Mutex<T>>` stays poisoned then, so every
+/// client is disconnected on its next request.
 ///
 pub trait Handler {
     fn handle(&mut self, req: &Value) -> Option<Value>;
+
+    /// Handle a request knowing the connection it comes from.
+    ///
+    /// Override it to keep per-connection state, e.g. for AUTH, SELECT or CLIENT SETNAME,
+    /// or to answer later with `Reply::Deferred`, e.g. for blocking commands;
+    /// by default, it calls `handle()`.
+    fn handle_with_ctx(&mut self, ctx: &mut ConnContext, req: &Value) -> Reply {
+        let _ = ctx;
+        self.handle(req).into()
+    }
+
+    /// Check the credentials of `HELLO <protover> AUTH <username> <password>`, and return the
+    /// error to reply if they are wrong, e.g. `-WRONGPASS`.
+    ///
+    /// `HELLO` itself is answered by the server; by default, `AUTH` in it is an error.
+    fn auth(&mut self, ctx: &mut ConnContext, username: &[u8], password: &[u8]) -> Result<(), Value> {
+        let _ = (ctx, username, password);
+        Err(Value::Error("ERR AUTH is not supported".to_owned()))
+    }
+
+    /// A client is connected, before its first request.
+    fn on_connect(&mut self, ctx: &mut ConnContext) {
+        let _ = ctx;
+    }
+
+    /// A client is disconnected, to release what it holds, e.g. locks or subscriptions.
+    fn on_disconnect(&mut self, ctx: &mut ConnContext, reason: &DisconnectReason) {
+        let _ = (ctx, reason);
+    }
+
+    /// Reading from or writing to a client failed, it is disconnected next.
+    fn on_error(&mut self, ctx: &mut ConnContext, err: &io::Error) {
+        let _ = (ctx, err);
+    }
+}
+
+/// HandlerFactory makes a handler for each client
+///
+/// The server calls `new_connection()` when it accepts a client and drops the handler
+/// once the client is disconnected, so that the handler may keep per-connection state,
+/// e.g. a transaction or a cursor.
+///
+/// `Arc<Mutex<T>>` is the factory sharing one handler with all the clients.
+///
+pub trait HandlerFactory {
+    fn new_connection(&self, ctx: &ConnContext) -> Box<dyn Handler + Send>;
+}
+
+impl<T: Handler + ?Sized> Handler for Arc<Mutex<T>> {
+    fn handle(&mut self, req: &Value) -> Option<Value> {
+        self.lock().unwrap().handle(req)
+    }
+
+    fn handle_with_ctx(&mut self, ctx: &mut ConnContext, req: &Value) -> Reply {
+        self.lock().unwrap().handle_with_ctx(ctx, req)
+    }
+
+    fn auth(&mut self, ctx: &mut ConnContext, username: &[u8], password: &[u8]) -> Result<(), Value> {
+        self.lock().unwrap().auth(ctx, username, password)
+    }
+
+    fn on_connect(&mut self, ctx: &mut ConnContext) {
+        self.lock().unwrap().on_connect(ctx)
+    }
+
+    fn on_disconnect(&mut self, ctx: &mut ConnContext, reason: &DisconnectReason) {
+        self.lock().unwrap().on_disconnect(ctx, reason)
+    }
+
+    fn on_error(&mut self, ctx: &mut ConnContext, err: &io::Error) {
+        self.lock().unwrap().on_error(ctx, err)
+    }
+}
+
+impl<T: Handler + Send + 'static> HandlerFactory for Arc<Mutex<T>> {
+    fn new_connection(&self, _ctx: &ConnContext) -> Box<dyn Handler + Send> {
+        Box::new(self.clone())
+    }
 }
 
 
@@ -0,0 +1,306 @@
+//! Listening sockets and client streams of either TCP or Unix domain
+//!
+
+use std::fmt;
+use std::fs;
+use std::io::{self, IoSlice, Read, Write, Result};
+use std::net::{self, TcpListener, TcpStream, Shutdown};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{self as unix, UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use rustls::ServerConfig;
+#[cfg(feature = "tls")]
+use tls::TlsStream;
+
+/// Address of a listener or of a client, either TCP or Unix domain.
+#[derive(Debug, Clone)]
+pub enum SockAddr {
+    Tcp(net::SocketAddr),
+    Unix(unix::SocketAddr),
+}
+
+impl SockAddr {
+    /// The TCP address, if any.
+    pub fn tcp(&self) -> Option<net::SocketAddr> {
+        match *self {
+            SockAddr::Tcp(addr) => Some(addr),
+            SockAddr::Unix(_) => None,
+        }
+    }
+
+    /// The path of a Unix domain address, if it is bound to one.
+    pub fn unix_path(&self) -> Option<&Path> {
+        match *self {
+            SockAddr::Tcp(_) => None,
+            SockAddr::Unix(ref addr) => addr.as_pathname(),
+        }
+    }
+}
+
+impl fmt::Display for SockAddr {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SockAddr::Tcp(ref addr) => write!(fmt, "{}", addr),
+            SockAddr::Unix(ref addr) => match addr.as_pathname() {
+                Some(path) => write!(fmt, "unix:{}", path.display()),
+                None => write!(fmt, "unix:(unnamed)"),
+            },
+        }
+    }
+}
+
+/// A listening socket
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    /// Accepted connections are wrapped in TLS sessions.
+    #[cfg(feature = "tls")]
+    Tls(TcpListener, Arc<ServerConfig>),
+    /// The socket file is removed when the listener is dropped.
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    pub fn bind_tcp(addr: &str) -> Result<Listener> {
+        TcpListener::bind(addr).map(Listener::Tcp)
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn bind_tls(addr: &str, config: Arc<ServerConfig>) -> Result<Listener> {
+        TcpListener::bind(addr).map(|listener| Listener::Tls(listener, config))
+    }
+
+    /// Bind a Unix domain socket at `path`, with file permissions `perm` when given.
+    ///
+    /// A stale socket file left by a dead server is removed first; a socket file some server
+    /// still accepts on, or any other kind of file, is an error.
+    pub fn bind_unix(path: &Path, perm: Option<u32>) -> Result<Listener> {
+        remove_stale_socket(path)?;
+        let listener = UnixListener::bind(path)?;
+        let listener = Listener::Unix(listener, path.to_path_buf());
+        if let Some(mode) = perm {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(listener)
+    }
+
+    pub fn accept(&self) -> Result<(Stream, SockAddr)> {
+        match *self {
+            Listener::Tcp(ref listener) => {
+                let (sock, addr) = listener.accept()?;
+                Ok((Stream::Tcp(sock), SockAddr::Tcp(addr)))
+            }
+            #[cfg(feature = "tls")]
+            Listener::Tls(ref listener, ref config) => {
+                let (sock, addr) = listener.accept()?;
+                Ok((Stream::Tls(Box::new(TlsStream::new(sock, config.clone())?)), SockAddr::Tcp(addr)))
+            }
+            Listener::Unix(ref listener, _) => {
+                let (sock, addr) = listener.accept()?;
+                Ok((Stream::Unix(sock), SockAddr::Unix(addr)))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SockAddr> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.local_addr().map(SockAddr::Tcp),
+            #[cfg(feature = "tls")]
+            Listener::Tls(ref listener, _) => listener.local_addr().map(SockAddr::Tcp),
+            Listener::Unix(ref listener, _) => listener.local_addr().map(SockAddr::Unix),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match *self {
+            Listener::Tcp(ref listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(feature = "tls")]
+            Listener::Tls(ref listener, _) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(ref listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Listener::Tcp(ref listener) => listener.as_raw_fd(),
+            #[cfg(feature = "tls")]
+            Listener::Tls(ref listener, _) => listener.as_raw_fd(),
+            Listener::Unix(ref listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, ref path) = *self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Remove the socket file at `path` unless a server is accepting on it.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !meta.file_type().is_socket() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is in use by another server", path.display()))),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+/// A client connection
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        match *self {
+            Stream::Tcp(ref sock) => sock.set_nonblocking(nonblocking),
+            Stream::Unix(ref sock) => sock.set_nonblocking(nonblocking),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref sock) => sock.get_ref().set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<SockAddr> {
+        match *self {
+            Stream::Tcp(ref sock) => sock.local_addr().map(SockAddr::Tcp),
+            Stream::Unix(ref sock) => sock.local_addr().map(SockAddr::Unix),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref sock) => sock.get_ref().local_addr().map(SockAddr::Tcp),
+        }
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        match *self {
+            Stream::Tcp(ref sock) => sock.shutdown(how),
+            Stream::Unix(ref sock) => sock.shutdown(how),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut sock) => sock.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut sock) => sock.read(buf),
+            Stream::Unix(ref mut sock) => sock.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut sock) => sock.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut sock) => sock.write(buf),
+            Stream::Unix(ref mut sock) => sock.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut sock) => sock.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut sock) => sock.write_vectored(bufs),
+            Stream::Unix(ref mut sock) => sock.write_vectored(bufs),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut sock) => sock.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match *self {
+            Stream::Tcp(ref mut sock) => sock.flush(),
+            Stream::Unix(ref mut sock) => sock.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut sock) => sock.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match *self {
+            Stream::Tcp(ref sock) => sock.as_raw_fd(),
+            Stream::Unix(ref sock) => sock.as_raw_fd(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref sock) => sock.get_ref().as_raw_fd(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+    use super::Listener;
+
+    fn socket_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("redif-{}-{}.sock", name, ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn bind_unix_with_permissions() {
+        let path = socket_path("perm");
+        let listener = Listener::bind_unix(&path, Some(0o600)).unwrap();
+        assert_eq!(0o600, fs::metadata(&path).unwrap().permissions().mode() & 0o777);
+        assert_eq!(Some(path.as_path()), listener.local_addr().unwrap().unix_path());
+
+        drop(listener);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn replace_stale_socket() {
+        let path = socket_path("stale");
+        // a std listener leaves its socket file behind
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let _listener = Listener::bind_unix(&path, None).unwrap();
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn refuse_live_socket_or_other_file() {
+        let path = socket_path("live");
+        let _listener = Listener::bind_unix(&path, None).unwrap();
+        let err = Listener::bind_unix(&path, None).unwrap_err();
+        assert_eq!(io::ErrorKind::AddrInUse, err.kind());
+
+        let path = socket_path("file");
+        File::create(&path).unwrap();
+        let err = Listener::bind_unix(&path, None).unwrap_err();
+        assert_eq!(io::ErrorKind::AlreadyExists, err.kind());
+        fs::remove_file(&path).unwrap();
+    }
+}
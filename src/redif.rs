@@ -1,13 +1,23 @@
-
 use std::collections::HashMap;
-use std::io::Result;
-use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::io::{self, Result, Write};
+use std::mem;
+use std::net::Shutdown;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use amy::{Notification, Event, Poller, Registrar};
-use frame_reader::FrameReader;
+use amy::{Notification, Event, Poller, Receiver, Registrar, Sender};
+use context::{ClientClass, ConnContext, DisconnectReason};
+use error::{self, Error};
+use frame_reader::{BufferPool, FrameReader};
 use frame_writer::FrameWriter;
+use listener::{Listener, Stream, SockAddr};
+use reply::{ReplyQueue, ReplySender, Token};
+use server::{Config, Server, ServerHandle};
+use value::{Protocol, Value};
+use worker::{self, Done, Job, Mailbox, Output, Session, WorkQueue, Workers};
 
-use Handler;
+use {Handler, HandlerFactory};
 use std::sync::{Arc,Mutex};
 
 /// Redif framework entry point
@@ -15,133 +25,1759 @@ use std::sync::{Arc,Mutex};
 /// redif should be invoke with a TCP port and a request handler 
 /// where user customize action taken on data.
 ///
+/// It listens on `0.0.0.0:port` with default settings, use `Server::builder()`
+/// to tune them.
+///
 pub fn run<T: Send + Handler + 'static>(port: u16, handler: Arc<Mutex<T>>) -> Result<()> {
+    Server::builder()
+        .bind(format!("0.0.0.0:{}", port))
+        .build(handler)?
+        .serve()
+}
+
+/// Redif framework entry point on a Unix domain socket
+///
+/// Same as `run()`, listening on the socket file at `path` instead of a TCP port.
+///
+pub fn run_unix<P: AsRef<Path>, T: Send + Handler + 'static>(path: P, handler: Arc<Mutex<T>>) -> Result<()> {
+    Server::builder()
+        .bind_unix(path)
+        .build(handler)?
+        .serve()
+}
+
+/// Spawn the event loops of a bound server, and return a handle to stop them.
+pub fn start(config: Config, listeners: Vec<Listener>, factory: Arc<dyn HandlerFactory + Send + Sync>) -> Result<ServerHandle> {
     use std::thread;
-    use std::sync::mpsc::channel;
 
-    info!("Listening on port {} ...", port);
+    let count = config.event_loops;
+    let stopped = Arc::new(AtomicBool::new(false));
+    let clients = Arc::new(AtomicUsize::new(0));
+    let (workers, work_queue) = if config.workers > 0 {
+        let (workers, work_queue) = Workers::start(config.workers)?;
+        (Some(workers), Some(work_queue))
+    } else {
+        (None, None)
+    };
 
-    let addr = format!("0.0.0.0:{}", port);
+    let mut local_addrs = Vec::with_capacity(listeners.len());
+    for listener in &listeners {
+        local_addrs.push(listener.local_addr()?);
+    }
 
-    let mut poller = Poller::new().unwrap();
-    let registrar = poller.get_registrar().unwrap();
+    // the first event loop accepts the clients, and hands them off to every event loop in turn
+    let mut pollers = Vec::with_capacity(count);
+    let mut handoff_txs = Vec::with_capacity(count);
+    for _ in 0..count {
+        let poller = Poller::new()?;
+        let mut registrar = poller.get_registrar()?;
+        let (handoff_tx, handoff_rx) = registrar.channel()?;
+        handoff_txs.push(handoff_tx);
+        pollers.push((poller, registrar, handoff_rx));
+    }
+    let mut listeners = Some(listeners);
+    let mut handoff_txs = Some(handoff_txs);
 
-    let (tx, rx) = channel();
+    let mut wakeups = Vec::with_capacity(count);
+    let mut threads = Vec::with_capacity(2 * count);
+    for (index, (mut poller, mut registrar, handoff_rx)) in pollers.into_iter().enumerate() {
+        let (shutdown_tx, shutdown_rx) = registrar.channel()?;
+        let (reply_tx, reply_rx) = registrar.channel()?;
+        let (done_tx, done_rx) = registrar.channel()?;
+        let poll_timeout = config.poll_timeout;
+        let stopped = stopped.clone();
 
-    let handle = thread::spawn(move || {
-        let listener = TcpListener::bind(&addr).unwrap();
-        listener.set_nonblocking(true).unwrap();
+        let listeners = if index == 0 { listeners.take().unwrap_or_default() } else { Vec::new() };
+        let mut listener_ids = HashMap::new();
+        for (listener_index, listener) in listeners.iter().enumerate() {
+            listener_ids.insert(registrar.register(listener, Event::Read)?, listener_index);
+        }
 
-        let listener_id = registrar.register(&listener, Event::Read).unwrap();
+        let idle_timer = registrar.set_interval(IDLE_CHECK_INTERVAL_MS)?;
 
-        let mut connections = HashMap::new();
+        let mut event_loop = EventLoop {
+            config: config.clone(),
+            index,
+            count,
+            registrar,
+            listeners,
+            listener_addrs: local_addrs.clone(),
+            listener_ids,
+            backoff_timers: HashMap::new(),
+            handoffs: if index == 0 { handoff_txs.take().unwrap_or_default() } else { Vec::new() },
+            next_loop: 0,
+            handoff: handoff_rx,
+            clients: clients.clone(),
+            idle_timer,
+            connections: HashMap::new(),
+            buffers: BufferPool::new(),
+            factory: factory.clone(),
+            reply_sender: ReplySender::new(reply_tx),
+            replies: reply_rx,
+            work_queue: work_queue.clone(),
+            finished_tx: Arc::new(done_tx),
+            finished: done_rx,
+        };
 
-        loop {
-            let notification : Notification = rx.recv().unwrap();
-            if notification.id == listener_id {
-               //let (mut socket, _) = listener.accept().unwrap();
-               let (socket, address) = listener.accept().unwrap();
-               socket.set_nonblocking(true).unwrap();
-
-               let socket_id = registrar.register(&socket, Event::Both).unwrap();
-               info!("DEBUG accept socket#{} {:?} {:?} ...", socket_id, &socket, &address);
-
-               let conn = Conn {
-                   sock: socket,
-                   addr: address,
-                   reader: FrameReader::new(1024 * 1024),
-                   writer: FrameWriter::new(),
-               };
-               connections.insert(socket_id, conn);
-            } else {
-                if let Err(e) = handle_poll_notification(&notification, &registrar, &mut connections, handler.clone()) {
-                    if let Some(conn) = connections.remove(&notification.id) {
-                        registrar.deregister(&conn.sock).unwrap();
-                        error!("fail to handle poll notification Event::{:?} sock#{} {} -- {}", &notification.event, &notification.id, &conn.addr, e);
+        threads.push(thread::Builder::new().name(format!("redif-{}", index)).spawn(move || {
+            let shutdown_id = shutdown_rx.get_id();
+
+            'poll: while !stopped.load(Ordering::SeqCst) {
+                let notifications = match poller.wait(poll_timeout) {
+                    Ok(notifications) => notifications,
+                    Err(e) => {
+                        error!(target: event_loop.config.log_target.as_str(), "fail to poll -- {}", e);
+                        break;
+                    }
+                };
+                for notification in notifications {
+                    if notification.id == shutdown_id {
+                        break 'poll;
+                    }
+                    event_loop.dispatch(&notification);
+                }
+            }
+
+            event_loop.shutdown(&mut poller);
+        })?);
+
+        wakeups.push(shutdown_tx);
+    }
+
+    Ok(ServerHandle::new(local_addrs, stopped, wakeups, threads, workers))
+}
+
+
+/// Milliseconds to pause accepting when the process runs out of file descriptors.
+const ACCEPT_BACKOFF_MS: usize = 100;
+
+/// Milliseconds between two checks of the idle clients, when they have a timeout.
+const IDLE_CHECK_INTERVAL_MS: usize = 1000;
+
+/// How the accept loop goes on after `accept()` failed
+#[derive(Debug, PartialEq)]
+enum AcceptFailure {
+    /// no more pending connection
+    Drained,
+    /// the connection broke before it was accepted, accept the next one
+    Aborted,
+    /// out of file descriptors or memory, pause accepting for a while
+    Exhausted,
+    /// unexpected failure, wait for the next readiness notification
+    Failed,
+}
+
+fn classify_accept_error(e: &io::Error) -> AcceptFailure {
+    match e.kind() {
+        io::ErrorKind::WouldBlock => return AcceptFailure::Drained,
+        // a TLS session could not be set up for the connection
+        io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::ConnectionReset | io::ErrorKind::InvalidData => return AcceptFailure::Aborted,
+        _ => {}
+    }
+    match e.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) | Some(libc::ENOBUFS) | Some(libc::ENOMEM) => AcceptFailure::Exhausted,
+        Some(libc::EPROTO) | Some(libc::EPERM) => AcceptFailure::Aborted,
+        _ => AcceptFailure::Failed,
+    }
+}
+
+/// A client accepted by the first event loop for another one: socket, address, listener index
+type Handoff = (Stream, SockAddr, usize);
+
+/// State of a connection thread
+struct EventLoop {
+    config: Config,
+    /// of this event loop, among `count`
+    index: usize,
+    count: usize,
+    registrar: Registrar,
+    listeners: Vec<Listener>,
+    listener_addrs: Vec<SockAddr>,
+    /// poller id => index of the listener
+    listener_ids: HashMap<usize, usize>,
+    /// timer id => index of the listener paused until it fires
+    backoff_timers: HashMap<usize, usize>,
+    /// to every event loop, on the first one only
+    handoffs: Vec<Sender<Handoff>>,
+    /// event loop to get the next client
+    next_loop: usize,
+    /// clients accepted for this event loop
+    handoff: Receiver<Handoff>,
+    /// number of clients of all the event loops
+    clients: Arc<AtomicUsize>,
+    /// interval timer to disconnect the idle clients, and the clients closing too slowly
+    idle_timer: usize,
+    connections: HashMap<usize, Conn>,
+    /// read buffers of the idle connections of this event loop
+    buffers: BufferPool,
+    factory: Arc<dyn HandlerFactory + Send + Sync>,
+    reply_sender: ReplySender,
+    /// deferred replies given to the `ReplySender`
+    replies: Receiver<(Token, Value)>,
+    /// none to run the handlers on the event loop thread
+    work_queue: Option<WorkQueue>,
+    finished_tx: Arc<Sender<Done>>,
+    /// batches of jobs run by the workers
+    finished: Receiver<Done>,
+}
+
+impl EventLoop {
+    fn dispatch(&mut self, notification: &Notification) {
+        let target = self.config.log_target.as_str();
+
+        if let Some(&index) = self.listener_ids.get(&notification.id) {
+            if self.backoff_timers.values().all(|&paused| paused != index) {
+                self.accept(index);
+            }
+        } else if let Some(index) = self.backoff_timers.remove(&notification.id) {
+            info!(target: target, "resume accepting on {}", &self.listener_addrs[index]);
+            self.accept(index);
+        } else if notification.id == self.handoff.get_id() {
+            while let Ok((socket, address, index)) = self.handoff.try_recv() {
+                self.add_connection(socket, address, index);
+            }
+        } else if notification.id == self.replies.get_id() {
+            self.send_deferred_replies();
+        } else if notification.id == self.finished.get_id() {
+            while let Ok(done) = self.finished.try_recv() {
+                self.complete(done);
+            }
+        } else if notification.id == self.idle_timer {
+            self.close_idle_connections();
+        } else {
+            match handle_poll_notification(notification, target, &mut self.connections) {
+                Ok(()) => {}
+                Err(Error::Io(e)) => {
+                    if e.kind() == io::ErrorKind::UnexpectedEof {
+                        self.disconnect(notification.id, DisconnectReason::Eof);
                     } else {
-                        error!("fail to handle poll notification Event::{:?} sock#{} -- {}", &notification.event, &notification.id, e);
+                        self.disconnect(notification.id, DisconnectReason::Io(e));
+                    }
+                    return;
+                }
+                Err(e) => {
+                    if let Some(conn) = self.connections.get_mut(&notification.id) {
+                        conn.failed = Some(e);
                     }
                 }
             }
+            self.schedule(notification.id);
+            self.close_if_done(notification.id);
         }
-    });
+    }
+
+    /// Run the jobs posted for a client since its mailbox was last scheduled.
+    fn schedule(&mut self, socket_id: usize) {
+        let mailbox = match self.connections.get_mut(&socket_id) {
+            Some(conn) if conn.unscheduled => {
+                conn.unscheduled = false;
+                conn.mailbox.clone()
+            }
+            _ => return,
+        };
+        self.run_mailbox(mailbox);
+    }
+
+    /// Run the jobs of a mailbox on a worker, or right away without workers.
+    fn run_mailbox(&mut self, mailbox: Arc<Mailbox>) {
+        match self.work_queue {
+            Some(ref work_queue) => work_queue.submit(mailbox),
+            None => {
+                let mut batches = Vec::new();
+                mailbox.run(|done| batches.push(done));
+                for done in batches {
+                    self.complete(done);
+                }
+            }
+        }
+    }
+
+    /// Send the replies to a batch of jobs run.
+    fn complete(&mut self, done: Done) {
+        let socket_id = self.socket_id(done.client);
+        if done.panicked {
+            if let Some(mut conn) = self.remove_connection(socket_id) {
+                let target = self.config.log_target.as_str();
+                error!(target: target, "drop client socket#{} {} on {} -- its handler panicked", socket_id, &conn.addr, &self.listener_addrs[conn.listener]);
+                let _ = conn.sock.shutdown(Shutdown::Both);
+            }
+            return;
+        }
+        let res = match self.connections.get_mut(&socket_id) {
+            Some(conn) => {
+                conn.in_flight -= done.requests;
+                conn.protocol = done.protocol;
+                conn.class = done.class;
+                conn.killed |= done.killed;
+                for output in done.outputs {
+                    match output {
+                        Output::Data(data) => conn.replies.push(&mut conn.writer, data),
+                        Output::Deferred(token) => conn.replies.defer(&mut conn.writer, token),
+                    }
+                }
+                conn.flush()
+            }
+            // the client is gone
+            None => return,
+        };
+        match res {
+            Ok(()) if self.output_limit_reached(socket_id) => self.disconnect(socket_id, DisconnectReason::OutputBufferLimit),
+            Ok(()) => self.close_if_done(socket_id),
+            Err(e) => self.disconnect(socket_id, DisconnectReason::Io(e)),
+        }
+    }
+
+    /// Whether the replies pending for a client reach the output buffer limit of its class.
+    fn output_limit_reached(&mut self, socket_id: usize) -> bool {
+        let config = &self.config;
+        self.connections.get_mut(&socket_id).is_some_and(|conn| {
+            let pending = conn.writer.len() + conn.replies.len();
+            config.output_buffer_limit(conn.class).reached(pending, &mut conn.soft_limit_since)
+        })
+    }
+
+    /// Close a connection killed by its handler, or failed or closed by the client once the
+    /// requests read before are run, once its deferred replies are sent.
+    ///
+    /// A connection closing is closed once its replies are sent.
+    fn close_if_done(&mut self, socket_id: usize) {
+        let reason = match self.connections.get_mut(&socket_id) {
+            Some(conn) if conn.closing => {
+                if conn.writer.is_empty() && !conn.replies.is_waiting() {
+                    self.finish_closing(socket_id);
+                }
+                return;
+            }
+            Some(conn) if conn.replies.is_waiting() => return,
+            Some(conn) if conn.killed => DisconnectReason::Killed,
+            Some(conn) if conn.in_flight == 0 && conn.failed.is_some() => match conn.failed.take() {
+                Some(e) => DisconnectReason::Protocol(e),
+                None => return,
+            },
+            // its requests came along with the end of the stream
+            Some(conn) if conn.in_flight == 0 && conn.reader.is_eof() => DisconnectReason::Eof,
+            _ => return,
+        };
+        self.disconnect(socket_id, reason);
+    }
+
+    /// Close a connection, and tell its handler why.
+    ///
+    /// A connection killed, idle, sending a malformed request or shut down is closed once its
+    /// replies are sent, without waiting for it on the event loop. A connection closing already
+    /// is closed at once.
+    fn disconnect(&mut self, socket_id: usize, reason: DisconnectReason) {
+        let target = self.config.log_target.as_str();
+        let (mailbox, unscheduled) = match self.connections.get_mut(&socket_id) {
+            // its handler was told when it started closing
+            Some(conn) if conn.closing => {
+                debug!(target: target, "close socket#{} {} before its replies are sent -- {}", socket_id, &conn.addr, reason);
+                self.finish_closing(socket_id);
+                return;
+            }
+            Some(conn) => (conn.mailbox.clone(), mem::replace(&mut conn.unscheduled, false)),
+            None => return,
+        };
+
+        match reason {
+            DisconnectReason::Killed | DisconnectReason::Timeout | DisconnectReason::Shutdown => {
+                if let Some(conn) = self.connections.get(&socket_id) {
+                    info!(target: target, "close client socket#{} {} on {} -- {}", socket_id, &conn.addr, &self.listener_addrs[conn.listener], reason);
+                }
+                self.close_after_flush(socket_id);
+            }
+            DisconnectReason::Protocol(ref e) => {
+                if let Some(conn) = self.connections.get_mut(&socket_id) {
+                    warn!(target: target, "drop client socket#{} {} on {} -- Protocol error: {}", socket_id, &conn.addr, &self.listener_addrs[conn.listener], e);
+                    // tell the client why its request is rejected, then close the connection like Redis does
+                    conn.replies.push(&mut conn.writer, Value::Error(format!("ERR Protocol error: {}", e)).encode());
+                }
+                self.close_after_flush(socket_id);
+            }
+            // half closed, it may still read the replies to its last requests
+            DisconnectReason::Eof if self.connections.get(&socket_id).is_some_and(|conn| !conn.writer.is_empty()) => {
+                if let Some(conn) = self.connections.get(&socket_id) {
+                    info!(target: target, "client socket#{} {} on {} closed", socket_id, &conn.addr, &self.listener_addrs[conn.listener]);
+                }
+                self.close_after_flush(socket_id);
+            }
+            DisconnectReason::Eof => {
+                if let Some(conn) = self.remove_connection(socket_id) {
+                    let target = self.config.log_target.as_str();
+                    info!(target: target, "client socket#{} {} on {} closed", socket_id, &conn.addr, &self.listener_addrs[conn.listener]);
+                }
+            }
+            DisconnectReason::Io(ref e) => {
+                if let Some(conn) = self.remove_connection(socket_id) {
+                    let target = self.config.log_target.as_str();
+                    error!(target: target, "drop client socket#{} {} on {} -- {}", socket_id, &conn.addr, &self.listener_addrs[conn.listener], e);
+                }
+            }
+            DisconnectReason::OutputBufferLimit => {
+                if let Some(mut conn) = self.remove_connection(socket_id) {
+                    let target = self.config.log_target.as_str();
+                    // the pending replies are dropped, not flushed
+                    warn!(target: target, "drop client socket#{} {} on {} -- {:?} output buffer limit reached with {} bytes pending",
+                          socket_id, &conn.addr, &self.listener_addrs[conn.listener], conn.class, conn.writer.len() + conn.replies.len());
+                    let _ = conn.sock.shutdown(Shutdown::Both);
+                }
+            }
+        }
+
+        // on_disconnect() after the requests already read
+        if mailbox.post(Job::Disconnect(reason)) || unscheduled {
+            self.run_mailbox(mailbox);
+        }
+    }
+
+    /// Stop reading from a connection, and close it once its replies are sent.
+    ///
+    /// A client which does not read them is closed by the idle timeout or by the output buffer
+    /// limit of its class, as if it was not closing.
+    fn close_after_flush(&mut self, socket_id: usize) {
+        let sent = match self.connections.get_mut(&socket_id) {
+            Some(conn) => {
+                conn.closing = true;
+                conn.flush().is_err() || (conn.writer.is_empty() && !conn.replies.is_waiting())
+            }
+            None => return,
+        };
+        if sent {
+            self.finish_closing(socket_id);
+        }
+    }
+
+    /// Close a connection closing, or any connection without flushing it.
+    fn finish_closing(&mut self, socket_id: usize) {
+        if let Some(mut conn) = self.remove_connection(socket_id) {
+            let _ = conn.sock.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Stop polling a connection, and forget it.
+    fn remove_connection(&mut self, socket_id: usize) -> Option<Conn> {
+        let conn = self.connections.remove(&socket_id)?;
+        let _ = self.registrar.deregister(&conn.sock);
+        self.clients.fetch_sub(1, Ordering::SeqCst);
+        Some(conn)
+    }
+
+    /// Send the deferred replies, along with the replies held back behind them.
+    fn send_deferred_replies(&mut self) {
+        let target = self.config.log_target.as_str();
+
+        let mut ready = Vec::new();
+        while let Ok((token, reply)) = self.replies.try_recv() {
+            let socket_id = self.socket_id(token.client_id());
+            let resolved = self.connections.get_mut(&socket_id).is_some_and(|conn| {
+                let data = worker::encode_reply(reply, conn.protocol);
+                conn.replies.resolve(&mut conn.writer, token, data)
+            });
+            if resolved {
+                ready.push(socket_id);
+            } else {
+                debug!(target: target, "drop deferred reply {:?} -- client gone", token);
+            }
+        }
+
+        for socket_id in ready {
+            let res = match self.connections.get_mut(&socket_id) {
+                Some(conn) => conn.flush(),
+                None => continue,
+            };
+            match res {
+                Ok(()) if self.output_limit_reached(socket_id) => self.disconnect(socket_id, DisconnectReason::OutputBufferLimit),
+                Ok(()) => self.close_if_done(socket_id),
+                Err(e) => self.disconnect(socket_id, DisconnectReason::Io(e)),
+            }
+        }
+    }
+
+    /// Disconnect the clients idle for longer than `idle_timeout`, and the clients closing over
+    /// the soft output buffer limit of their class for too long.
+    ///
+    /// Clients waiting for a deferred reply are not idle.
+    fn close_idle_connections(&mut self) {
+        let timeout = Duration::from_secs(self.config.idle_timeout);
+        let socket_ids: Vec<usize> = self.connections.keys().cloned().collect();
+        for socket_id in socket_ids {
+            let (idle, closing) = match self.connections.get(&socket_id) {
+                Some(conn) => (self.config.idle_timeout > 0 && conn.in_flight == 0 && !conn.replies.is_waiting()
+                               && conn.last_active.elapsed() > timeout, conn.closing),
+                None => continue,
+            };
+            if idle {
+                self.disconnect(socket_id, DisconnectReason::Timeout);
+            } else if closing && self.output_limit_reached(socket_id) {
+                // no more replies come to check the limit
+                self.disconnect(socket_id, DisconnectReason::OutputBufferLimit);
+            }
+        }
+    }
+
+    /// Accept the pending connections of a listener, and spread them over the event loops in turn.
+    fn accept(&mut self, index: usize) {
+        for (mut socket, address) in self.accept_clients(index) {
+            let target = self.config.log_target.as_str();
+            if self.clients.load(Ordering::SeqCst) >= self.config.max_clients {
+                warn!(target: target, "refuse {:?} -- max number of clients {} reached", &address, self.config.max_clients);
+                let _ = socket.write_all(&Value::Error("ERR max number of clients reached".to_owned()).encode());
+                continue;
+            }
+            self.clients.fetch_add(1, Ordering::SeqCst);
+
+            let next = self.next_loop;
+            self.next_loop = (next + 1) % self.handoffs.len();
+            if next == self.index {
+                self.add_connection(socket, address, index);
+            } else if self.handoffs[next].send((socket, address, index)).is_err() {
+                error!(target: target, "drop client -- fail to hand it off to event loop {}", next);
+                self.clients.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Accept the pending connections of a listener.
+    ///
+    /// Listeners are edge triggered, so accept until there is no more, or the listener is paused.
+    fn accept_clients(&mut self, index: usize) -> Vec<(Stream, SockAddr)> {
+        let target = self.config.log_target.as_str();
+        let mut accepted = Vec::new();
 
-    let handle_poller = thread::Builder::new().name(format!("poller")).spawn(move || {
         loop {
-            let notifications = poller.wait(5000).unwrap();
-            for n in notifications {
-                tx.send(n).unwrap();
+            match self.listeners[index].accept() {
+                Ok(client) => accepted.push(client),
+                Err(e) => match classify_accept_error(&e) {
+                    AcceptFailure::Drained => return accepted,
+                    AcceptFailure::Aborted => {
+                        debug!(target: target, "skip aborted connection on {} -- {}", &self.listener_addrs[index], e);
+                    }
+                    AcceptFailure::Exhausted => {
+                        error!(target: target, "pause accepting on {} for {} ms -- {}", &self.listener_addrs[index], ACCEPT_BACKOFF_MS, e);
+                        match self.registrar.set_timeout(ACCEPT_BACKOFF_MS) {
+                            Ok(timer_id) => {
+                                self.backoff_timers.insert(timer_id, index);
+                            }
+                            Err(e) => error!(target: target, "fail to set accept backoff timer -- {}", e),
+                        }
+                        return accepted;
+                    }
+                    AcceptFailure::Failed => {
+                        error!(target: target, "fail to accept on {} -- {}", &self.listener_addrs[index], e);
+                        return accepted;
+                    }
+                },
             }
         }
-    }).unwrap();
+    }
 
-    handle.join().unwrap();
-    handle_poller.join().unwrap();
+    /// Register a client accepted on listener `index`, and tell its handler.
+    fn add_connection(&mut self, socket: Stream, address: SockAddr, index: usize) {
+        let target = self.config.log_target.as_str();
 
-    Ok(())
-}
+        if let Err(e) = socket.set_nonblocking(true) {
+            error!(target: target, "drop {} -- fail to set non-blocking: {}", &address, e);
+            self.clients.fetch_sub(1, Ordering::SeqCst);
+            return;
+        }
+
+        let socket_id = match self.registrar.register(&socket, Event::Both) {
+            Ok(socket_id) => socket_id,
+            Err(e) => {
+                error!(target: target, "drop {} -- fail to register: {}", &address, e);
+                self.clients.fetch_sub(1, Ordering::SeqCst);
+                return;
+            }
+        };
+        info!(target: target, "DEBUG accept socket#{} {:?} {:?} on {} ...", socket_id, &socket, &address, &self.listener_addrs[index]);
+
+        let local_addr = socket.local_addr().unwrap_or_else(|_| self.listener_addrs[index].clone());
+        let ctx = ConnContext::new(self.client_id(socket_id), address.clone(), local_addr, index, self.reply_sender.clone());
+        let handler = self.factory.new_connection(&ctx);
+        let mailbox = Mailbox::new(Session::new(ctx, handler), self.finished_tx.clone());
+        mailbox.post(Job::Connect);
+
+        let conn = Conn {
+            sock: socket,
+            addr: address,
+            listener: index,
+            reader: self.frame_reader(),
+            writer: FrameWriter::new(),
+            mailbox: mailbox.clone(),
+            unscheduled: false,
+            in_flight: 0,
+            protocol: Protocol::default(),
+            class: ClientClass::default(),
+            killed: false,
+            failed: None,
+            last_active: Instant::now(),
+            replies: ReplyQueue::new(),
+            soft_limit_since: None,
+            closing: false,
+        };
+        self.connections.insert(socket_id, conn);
+        self.run_mailbox(mailbox);
+    }
+
+    /// Id of the client of a socket, unique across the event loops.
+    fn client_id(&self, socket_id: usize) -> usize {
+        socket_id * self.count + self.index
+    }
+
+    fn socket_id(&self, client_id: usize) -> usize {
+        client_id / self.count
+    }
 
+    #[cfg(not(feature = "bytes"))]
+    fn frame_reader(&self) -> FrameReader {
+        FrameReader::new(self.config.max_frame_size, self.buffers.clone())
+    }
+
+    #[cfg(feature = "bytes")]
+    fn frame_reader(&self) -> FrameReader {
+        if self.config.shared_data {
+            FrameReader::shared(self.config.max_frame_size)
+        } else {
+            FrameReader::new(self.config.max_frame_size, self.buffers.clone())
+        }
+    }
+
+    /// Stop accepting, then flush and close every connection.
+    ///
+    /// The replies pending are sent to all the clients at once, for `SHUTDOWN_FLUSH_TIMEOUT_MS`
+    /// at most.
+    fn shutdown(mut self, poller: &mut Poller) {
+        let target = self.config.log_target.as_str();
+
+        info!(target: target, "Shutting down, closing {} connections ...", self.connections.len());
+
+        // stop accepting before draining the clients
+        for listener in self.listeners.drain(..) {
+            let _ = self.registrar.deregister(&listener);
+        }
+
+        let socket_ids: Vec<usize> = self.connections.keys().cloned().collect();
+        for socket_id in socket_ids {
+            self.disconnect(socket_id, DisconnectReason::Shutdown);
+        }
+
+        let deadline = Instant::now() + Duration::from_millis(SHUTDOWN_FLUSH_TIMEOUT_MS);
+        while !self.connections.is_empty() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::from_millis(0) {
+                break;
+            }
+            match poller.wait(left.as_millis() as usize + 1) {
+                Ok(notifications) => {
+                    for notification in notifications {
+                        self.dispatch(&notification);
+                    }
+                }
+                Err(e) => {
+                    error!(target: self.config.log_target.as_str(), "fail to poll -- {}", e);
+                    break;
+                }
+            }
+        }
 
+        // too slow to read their replies, or handed off meanwhile
+        let socket_ids: Vec<usize> = self.connections.keys().cloned().collect();
+        for socket_id in socket_ids {
+            self.finish_closing(socket_id);
+        }
+    }
+}
 
 struct Conn {
-    sock: TcpStream,
-    addr: SocketAddr,
+    sock: Stream,
+    addr: SockAddr,
+    /// index of the listener which accepted the connection
+    listener: usize,
     reader: FrameReader,
-    writer: FrameWriter
+    writer: FrameWriter,
+    /// handler and context of the client
+    mailbox: Arc<Mailbox>,
+    /// whether jobs were posted since the mailbox was last scheduled
+    unscheduled: bool,
+    /// requests posted, not run yet
+    in_flight: usize,
+    /// negotiated by `HELLO`, as of the last requests run
+    protocol: Protocol,
+    /// for the output buffer limit, as of the last requests run
+    class: ClientClass,
+    killed: bool,
+    /// protocol error to reply once the requests before it are run
+    failed: Option<Error>,
+    /// when a request was last read, or a reply written
+    last_active: Instant,
+    /// replies held back behind the deferred ones
+    replies: ReplyQueue,
+    /// since when the replies pending reach the soft output buffer limit
+    soft_limit_since: Option<Instant>,
+    /// whether the connection is closed once its replies are sent
+    closing: bool,
+}
+
+impl Conn {
+    /// Write the replies queued until the socket would block.
+    fn flush(&mut self) -> Result<()> {
+        let pending = self.writer.len();
+        self.writer.write(&mut self.sock, None)?;
+        flush_stream(&mut self.sock)?;
+        if self.writer.len() < pending {
+            self.last_active = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+/// Time allowed to flush the replies pending for a client on shutdown.
+const SHUTDOWN_FLUSH_TIMEOUT_MS: u64 = 1000;
+
+/// Push out what the stream buffers on its own, e.g. TLS records.
+fn flush_stream(sock: &mut Stream) -> Result<()> {
+    match sock.flush() {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        res => res,
+    }
+}
+
+/// Serve a readiness notification of a client: read and post its requests, then write the
+/// replies queued until the socket would block.
+///
+/// Sockets are registered edge triggered for both events, so a writer which hit `WouldBlock`
+/// waits for the next writable notification, and reading for the next readable one.
+fn handle_poll_notification(notification: &Notification,
+                            target: &str,
+                            connections: &mut HashMap<usize, Conn>) -> error::Result<()> {
+    let conn = match connections.get_mut(&notification.id) {
+        Some(conn) => conn,
+        None => {
+            error!(target: target, "SKIP notification for un-registered socket#{}", notification.id);
+            return Ok(());
+        }
+    };
+
+    if notification.event.writable() {
+        conn.writer.writable();
+    }
+
+    // nothing more is read after a malformed request, once killed, closed by the client, or closing
+    if notification.event.readable() && conn.failed.is_none() && !conn.killed && !conn.reader.is_eof() && !conn.closing {
+        // requests before a malformed one are still served, ahead of the error reply
+        let read = match conn.reader.read(&mut conn.sock) {
+            Err(e) if !e.is_protocol() => return Err(e),
+            read => read,
+        };
+        conn.last_active = Instant::now();
+
+        for msg in conn.reader.iter_mut() {
+            conn.in_flight += 1;
+            conn.unscheduled |= conn.mailbox.post(Job::Request(msg));
+        }
+        read?;
+    }
+
+    conn.flush()?;
+    Ok(())
 }
 
-// Assume only TcpStream notifications for now. Error handling is done by the (elided) caller.
-fn handle_poll_notification<T: Send + Handler>(notification: &Notification,
-                            _registrar: &Registrar,
-                            connections: &mut HashMap<usize, Conn>,
-                            handler: Arc<Mutex<T>>) -> Result<()> {
-    //info!("DEBUG handle notification {:?} ...", notification);
-    use value::Value;
-
-    if let Some(conn) = connections.get_mut(&notification.id) {
-        match notification.event {
-            Event::Read => {
-                // Try to read the data from the connection sock. Ignore the amount of bytes read.
-                let _sz = conn.reader.read(&mut conn.sock)?;
-
-                // Iterate through all available complete messages. Note that this iterator is mutable
-                // in a non-traditional sense. It returns each complete message only once and removes it
-                // from the reader.
-                for msg in conn.reader.iter_mut() {
-                    //println!("Received a complete message: {:?}", ::std::str::from_utf8(&msg).unwrap());
-                    println!("Received a complete message: {:?}", &msg);
-
-                    let data = Value::Status("OK".to_string()).encode();
-                    let _sz = conn.writer.write(&mut conn.sock, Some(data))?;
-                    //info!("DEBUG write {}", _sz);
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::mem;
+    use std::net::{Shutdown, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use libc;
+    use super::{classify_accept_error, AcceptFailure};
+    use super::super::{ClientClass, ConnContext, DisconnectReason, Handler, HandlerFactory, OutputBufferLimit, Reply, Server, Value, encode_slice};
+
+    #[test]
+    fn classify_accept_errors() {
+        let cases = [
+            (io::Error::from(io::ErrorKind::WouldBlock), AcceptFailure::Drained),
+            (io::Error::from_raw_os_error(libc::EAGAIN), AcceptFailure::Drained),
+            (io::Error::from_raw_os_error(libc::EINTR), AcceptFailure::Aborted),
+            (io::Error::from_raw_os_error(libc::ECONNABORTED), AcceptFailure::Aborted),
+            (io::Error::from_raw_os_error(libc::EPROTO), AcceptFailure::Aborted),
+            (io::Error::from_raw_os_error(libc::EMFILE), AcceptFailure::Exhausted),
+            (io::Error::from_raw_os_error(libc::ENFILE), AcceptFailure::Exhausted),
+            (io::Error::from_raw_os_error(libc::ENOMEM), AcceptFailure::Exhausted),
+            (io::Error::from_raw_os_error(libc::EBADF), AcceptFailure::Failed),
+        ];
+        for (e, want) in cases.iter() {
+            assert_eq!(*want, classify_accept_error(e), "{}", e);
+        }
+    }
+
+    struct Ping;
+
+    impl Handler for Ping {
+        fn handle(&mut self, _req: &Value) -> Option<Value> {
+            Some(Value::Status("PONG".to_owned()))
+        }
+    }
+
+    #[test]
+    fn accept_burst_of_connections() {
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .build(Arc::new(Mutex::new(Ping)))
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        // all pending before the server wakes up, a single readiness edge for the listener
+        let mut clients: Vec<TcpStream> = (0..32).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        for client in clients.iter_mut() {
+            client.write_all(&encode_slice(&["PING"])).unwrap();
+            let mut reply = [0; 7];
+            client.read_exact(&mut reply).unwrap();
+            assert_eq!(b"+PONG\r\n", &reply);
+        }
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn reply_protocol_error() {
+        for &workers in [0, 2].iter() {
+            let handle = Server::builder()
+                .bind("127.0.0.1:0")
+                .workers(workers)
+                .build(Arc::new(Mutex::new(Ping)))
+                .unwrap()
+                .start()
+                .unwrap();
+            let addr = handle.local_addr().tcp().unwrap();
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"*1\r\n$4\r\nPING\r\n*1\r\n$x\r\n").unwrap();
+            let mut reply = String::new();
+            client.read_to_string(&mut reply).unwrap();
+            assert_eq!("+PONG\r\n-ERR Protocol error: invalid bulk length\r\n", reply);
+
+            handle.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn serve_inline_commands() {
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .build(Arc::new(Mutex::new(Ping)))
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"PING\r\nECHO \"hello world\"\nECHO \"oops\r\n").unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!("+PONG\r\n+PONG\r\n-ERR Protocol error: unbalanced quotes in request\r\n", reply);
+
+        handle.shutdown().unwrap();
+    }
+
+    /// Reads a reply from a blocking stream
+    fn read_value(client: &mut TcpStream) -> Value {
+        let mut buf = Vec::new();
+        loop {
+            let mut chunk = [0; 1024];
+            let n = client.read(&mut chunk).unwrap();
+            assert!(n > 0, "connection closed");
+            buf.extend_from_slice(&chunk[..n]);
+            let (val, offset) = Value::decode(&buf, 0).unwrap();
+            if offset > 0 {
+                assert_eq!(buf.len(), offset);
+                return val;
+            }
+        }
+    }
+
+    /// Shrinks the receive buffer of a client, for the server to hit `WouldBlock` early
+    fn small_receive_buffer(client: &TcpStream) {
+        let size: libc::c_int = 64 * 1024;
+        let res = unsafe {
+            libc::setsockopt(client.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVBUF,
+                             &size as *const libc::c_int as *const libc::c_void,
+                             mem::size_of::<libc::c_int>() as libc::socklen_t)
+        };
+        assert_eq!(0, res);
+    }
+
+    /// Replies with a map, or with the client name to `GETNAME`, and checks the password of `HELLO`
+    struct Resp3;
+
+    impl Handler for Resp3 {
+        fn handle(&mut self, _req: &Value) -> Option<Value> {
+            Some(Value::Map(vec![(Value::Data(b"ok".to_vec()), Value::Boolean(true))]))
+        }
+
+        fn handle_with_ctx(&mut self, ctx: &mut ConnContext, req: &Value) -> Reply {
+            if *req == Value::Bulk(vec![Value::Data(b"GETNAME".to_vec())]) {
+                return Reply::Value(ctx.name().map_or(Value::Nil, |name| Value::Data(name.as_bytes().to_vec())));
+            }
+            self.handle(req).into()
+        }
+
+        fn auth(&mut self, _ctx: &mut ConnContext, username: &[u8], password: &[u8]) -> Result<(), Value> {
+            if username == b"default" && password == b"secret" {
+                Ok(())
+            } else {
+                Err(Value::Error("WRONGPASS invalid username-password pair".to_owned()))
+            }
+        }
+    }
+
+    #[test]
+    fn negotiate_protocol_with_hello() {
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .build(Arc::new(Mutex::new(Resp3)))
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+
+        // RESP2 until HELLO 3
+        client.write_all(&encode_slice(&["GET", "a"])).unwrap();
+        assert_eq!(Value::Bulk(vec![Value::Data(b"ok".to_vec()), Value::Int(1)]), read_value(&mut client));
+
+        client.write_all(&encode_slice(&["HELLO", "4"])).unwrap();
+        assert_eq!(Value::Error("NOPROTO unsupported protocol version".to_owned()), read_value(&mut client));
+        client.write_all(&encode_slice(&["HELLO", "3", "AUTH", "default"])).unwrap();
+        assert_eq!(Value::Error("ERR Syntax error in HELLO option 'AUTH'".to_owned()), read_value(&mut client));
+
+        // nothing changes unless the password is right
+        client.write_all(&encode_slice(&["HELLO", "3", "AUTH", "default", "wrong", "SETNAME", "x"])).unwrap();
+        assert_eq!(Value::Error("WRONGPASS invalid username-password pair".to_owned()), read_value(&mut client));
+        client.write_all(b"GETNAME\r\n").unwrap();
+        assert_eq!(Value::Nil, read_value(&mut client));
+
+        client.write_all(&encode_slice(&["hello", "3", "auth", "default", "secret", "setname", "x"])).unwrap();
+        match read_value(&mut client) {
+            Value::Map(ref fields) => {
+                assert!(fields.contains(&(Value::Data(b"server".to_vec()), Value::Data(b"redif".to_vec()))));
+                assert!(fields.contains(&(Value::Data(b"proto".to_vec()), Value::Int(3))));
+            }
+            val => panic!("unexpected {:?}", val),
+        }
+        client.write_all(&encode_slice(&["GET", "a"])).unwrap();
+        assert_eq!(Value::Map(vec![(Value::Data(b"ok".to_vec()), Value::Boolean(true))]), read_value(&mut client));
+        client.write_all(b"GETNAME\r\n").unwrap();
+        assert_eq!(Value::Data(b"x".to_vec()), read_value(&mut client));
+
+        // back to RESP2, and the fields of HELLO come as an array
+        client.write_all(&encode_slice(&["HELLO", "2"])).unwrap();
+        match read_value(&mut client) {
+            Value::Bulk(ref fields) => assert_eq!(14, fields.len()),
+            val => panic!("unexpected {:?}", val),
+        }
+
+        handle.shutdown().unwrap();
+    }
+
+    /// Keeps the selected db and the client name in the connection context
+    struct Session;
+
+    impl Handler for Session {
+        fn handle(&mut self, _req: &Value) -> Option<Value> {
+            unreachable!()
+        }
+
+        fn handle_with_ctx(&mut self, ctx: &mut ConnContext, req: &Value) -> Reply {
+            let args: Vec<String> = match *req {
+                Value::Bulk(ref args) => args.iter().map(|arg| String::from_utf8_lossy(arg.as_slice()).into_owned()).collect(),
+                _ => return Value::Error("ERR unknown request".to_owned()).into(),
+            };
+            let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+            // count the requests of the connection
+            match ctx.user_data_mut::<usize>() {
+                Some(count) => *count += 1,
+                None => ctx.set_user_data(1_usize),
+            }
+            Reply::Value(match args.as_slice() {
+                ["SELECT", db] => {
+                    ctx.set_db(db.parse().unwrap());
+                    Value::Status("OK".to_owned())
                 }
-            },
-            Event::Write => {
-                // Attempt to write *all* existing data queued for writing. `None` as the second
-                // parameter means no new data.
-                conn.writer.write(&mut conn.sock, None)?;
-            },
-            Event::Both => {
-                //info!("DEBUG socket#{} Event::Both read ...", notification.id);
-                let _sz = conn.reader.read(&mut conn.sock)?;
-                //info!("DEBUG socket#{} Event::Both read {} bytes", notification.id, _sz);
-
-                for msg in conn.reader.iter_mut() {
-                    //println!("Received a complete message: {:?}", &msg);
-                    let mut handler = handler.lock().unwrap();
-                    if let Some(data) = handler.handle( &msg ) {
-                        conn.writer.write(&mut conn.sock, Some(data.encode()))?;
-                    }
+                ["CLIENT", "SETNAME", name] => {
+                    ctx.set_name(Some(name.to_string()));
+                    Value::Status("OK".to_owned())
                 }
+                ["WHOAMI"] => Value::Data(format!("{} {} {} {:?} {}", ctx.id(), ctx.peer_addr(), ctx.db(),
+                                                  ctx.name(), ctx.user_data::<usize>().unwrap()).into_bytes()),
+                _ => Value::Error("ERR unknown command".to_owned()),
+            })
+        }
+    }
 
-                let _sz = conn.writer.write(&mut conn.sock, None)?;
+    #[test]
+    fn keep_state_in_connection_context() {
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .build(Arc::new(Mutex::new(Session)))
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        first.write_all(&encode_slice(&["SELECT", "3"])).unwrap();
+        assert_eq!(Value::Status("OK".to_owned()), read_value(&mut first));
+        first.write_all(&encode_slice(&["CLIENT", "SETNAME", "first"])).unwrap();
+        assert_eq!(Value::Status("OK".to_owned()), read_value(&mut first));
+
+        second.write_all(&encode_slice(&["WHOAMI"])).unwrap();
+        let second_id = match String::from_utf8(read_value(&mut second).as_slice().to_vec()).unwrap().split(' ').collect::<Vec<_>>().as_slice() {
+            [id, peer, "0", "None", "1"] => {
+                assert_eq!(second.local_addr().unwrap().to_string(), *peer);
+                id.to_string()
             }
+            fields => panic!("unexpected {:?}", fields),
+        };
+
+        first.write_all(&encode_slice(&["WHOAMI"])).unwrap();
+        match String::from_utf8(read_value(&mut first).as_slice().to_vec()).unwrap().split(' ').collect::<Vec<_>>().as_slice() {
+            [id, peer, "3", "Some(\"first\")", "3"] => {
+                assert_ne!(second_id, *id);
+                assert_eq!(first.local_addr().unwrap().to_string(), *peer);
+            }
+            fields => panic!("unexpected {:?}", fields),
         }
-    } else {
-        error!("SKIP notification for un-registered socket#{}", notification.id);
+
+        handle.shutdown().unwrap();
     }
 
-    Ok(())
-}
+    /// Queues the requests from MULTI until EXEC, like a transaction
+    struct Transaction {
+        queued: Option<Vec<Value>>,
+        live: Arc<AtomicUsize>,
+    }
+
+    impl Handler for Transaction {
+        fn handle(&mut self, req: &Value) -> Option<Value> {
+            let name = match *req {
+                Value::Bulk(ref args) => args[0].as_slice().to_vec(),
+                _ => Vec::new(),
+            };
+            Some(match (&name[..], self.queued.as_mut()) {
+                (b"MULTI", None) => {
+                    self.queued = Some(Vec::new());
+                    Value::Status("OK".to_owned())
+                }
+                (b"EXEC", Some(_)) => Value::Bulk(self.queued.take().unwrap()),
+                (_, Some(queued)) => {
+                    queued.push(req.clone());
+                    Value::Status("QUEUED".to_owned())
+                }
+                _ => Value::Error("ERR unknown command".to_owned()),
+            })
+        }
+    }
+
+    impl Drop for Transaction {
+        fn drop(&mut self) {
+            self.live.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    struct Transactions {
+        live: Arc<AtomicUsize>,
+    }
+
+    impl HandlerFactory for Transactions {
+        fn new_connection(&self, _ctx: &ConnContext) -> Box<dyn Handler + Send> {
+            self.live.fetch_add(1, Ordering::SeqCst);
+            Box::new(Transaction { queued: None, live: self.live.clone() })
+        }
+    }
+
+    #[test]
+    fn handler_per_connection() {
+        let live = Arc::new(AtomicUsize::new(0));
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .build_with_factory(Transactions { live: live.clone() })
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
 
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        first.write_all(&encode_slice(&["MULTI"])).unwrap();
+        assert_eq!(Value::Status("OK".to_owned()), read_value(&mut first));
+        first.write_all(&encode_slice(&["SET", "a", "1"])).unwrap();
+        assert_eq!(Value::Status("QUEUED".to_owned()), read_value(&mut first));
+        assert_eq!(2, live.load(Ordering::SeqCst));
 
+        // the transaction of the first client is not seen by the second
+        second.write_all(&encode_slice(&["EXEC"])).unwrap();
+        assert_eq!(Value::Error("ERR unknown command".to_owned()), read_value(&mut second));
+        first.write_all(&encode_slice(&["EXEC"])).unwrap();
+        assert_eq!(Value::Bulk(vec![Value::Bulk(vec![Value::Data(b"SET".to_vec()), Value::Data(b"a".to_vec()), Value::Data(b"1".to_vec())])]),
+                   read_value(&mut first));
+
+        // the handler goes away with its client
+        drop(second);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while live.load(Ordering::SeqCst) != 1 {
+            assert!(Instant::now() < deadline, "handler of a closed connection is not dropped");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        handle.shutdown().unwrap();
+        assert_eq!(0, live.load(Ordering::SeqCst));
+    }
+
+    /// Records the connections and disconnections, by client address
+    struct Lifecycle {
+        events: Vec<String>,
+    }
+
+    impl Handler for Lifecycle {
+        fn handle(&mut self, _req: &Value) -> Option<Value> {
+            unreachable!()
+        }
+
+        fn handle_with_ctx(&mut self, ctx: &mut ConnContext, req: &Value) -> Reply {
+            if *req == Value::Bulk(vec![Value::Data(b"QUIT".to_vec())]) {
+                ctx.kill();
+            }
+            Value::Status("OK".to_owned()).into()
+        }
+
+        fn on_connect(&mut self, ctx: &mut ConnContext) {
+            self.events.push(format!("connect {}", ctx.peer_addr()));
+        }
+
+        fn on_disconnect(&mut self, ctx: &mut ConnContext, reason: &DisconnectReason) {
+            self.events.push(format!("disconnect {} {}", ctx.peer_addr(), reason));
+        }
+    }
+
+    /// Waits until the handler records `event`
+    fn wait_for_event(handler: &Mutex<Lifecycle>, event: String) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !handler.lock().unwrap().events.contains(&event) {
+            assert!(Instant::now() < deadline, "no {:?} in {:?}", event, handler.lock().unwrap().events);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn connection_lifecycle_hooks() {
+        connection_lifecycle(0);
+        connection_lifecycle(2);
+    }
+
+    fn connection_lifecycle(workers: usize) {
+        let handler = Arc::new(Mutex::new(Lifecycle { events: Vec::new() }));
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .idle_timeout(1)
+            .workers(workers)
+            .build(handler.clone())
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        // the requests pipelined after QUIT are dropped
+        let mut client = TcpStream::connect(addr).unwrap();
+        let peer = client.local_addr().unwrap();
+        client.write_all(b"PING\r\nQUIT\r\nPING\r\n").unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert_eq!("+OK\r\n+OK\r\n", reply);
+        wait_for_event(&handler, format!("disconnect {} killed", peer));
+        assert_eq!(format!("connect {}", peer), handler.lock().unwrap().events[0]);
+
+        let client = TcpStream::connect(addr).unwrap();
+        let peer = client.local_addr().unwrap();
+        wait_for_event(&handler, format!("connect {}", peer));
+        drop(client);
+        wait_for_event(&handler, format!("disconnect {} closed", peer));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let peer = client.local_addr().unwrap();
+        client.write_all(b"*1\r\n$x\r\n").unwrap();
+        wait_for_event(&handler, format!("disconnect {} Protocol error: invalid bulk length", peer));
+
+        let idle = TcpStream::connect(addr).unwrap();
+        let peer = idle.local_addr().unwrap();
+        wait_for_event(&handler, format!("disconnect {} idle timeout", peer));
+
+        let active = TcpStream::connect(addr).unwrap();
+        let peer = active.local_addr().unwrap();
+        wait_for_event(&handler, format!("connect {}", peer));
+        handle.shutdown().unwrap();
+        wait_for_event(&handler, format!("disconnect {} shutdown", peer));
+    }
+
+    #[test]
+    fn reply_to_half_closed_clients() {
+        for &workers in [0, 2].iter() {
+            let handler = Arc::new(Mutex::new(Lifecycle { events: Vec::new() }));
+            let handle = Server::builder()
+                .bind("127.0.0.1:0")
+                .workers(workers)
+                .max_clients(1)
+                .build(handler.clone())
+                .unwrap()
+                .start()
+                .unwrap();
+            let addr = handle.local_addr().tcp().unwrap();
+
+            // like `echo PING | nc -N`, the requests and the end of the stream arrive together
+            for _ in 0..2 {
+                let mut client = TcpStream::connect(addr).unwrap();
+                client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+                let peer = client.local_addr().unwrap();
+                client.write_all(b"PING\r\nPING\r\n").unwrap();
+                client.shutdown(Shutdown::Write).unwrap();
+                let mut reply = String::new();
+                client.read_to_string(&mut reply).unwrap();
+                assert_eq!("+OK\r\n+OK\r\n", reply);
+                // and its place is free for the next one
+                wait_for_event(&handler, format!("disconnect {} closed", peer));
+            }
+
+            handle.shutdown().unwrap();
+        }
+    }
+
+    /// Panics on `PANIC`, with a handler per client
+    struct Panicky;
+
+    impl Handler for Panicky {
+        fn handle(&mut self, req: &Value) -> Option<Value> {
+            if *req == Value::Bulk(vec![Value::Data(b"PANIC".to_vec())]) {
+                panic!("PANIC requested");
+            }
+            Some(Value::Status("OK".to_owned()))
+        }
+    }
+
+    impl HandlerFactory for Panicky {
+        fn new_connection(&self, _ctx: &ConnContext) -> Box<dyn Handler + Send> {
+            Box::new(Panicky)
+        }
+    }
+
+    #[test]
+    fn disconnect_client_of_panicking_handler() {
+        for &workers in [0, 1].iter() {
+            let handle = Server::builder()
+                .bind("127.0.0.1:0")
+                .workers(workers)
+                .build_with_factory(Panicky)
+                .unwrap()
+                .start()
+                .unwrap();
+            let addr = handle.local_addr().tcp().unwrap();
+
+            for _ in 0..2 {
+                let mut client = TcpStream::connect(addr).unwrap();
+                client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+                client.write_all(b"PING\r\n").unwrap();
+                assert_eq!(Value::Status("OK".to_owned()), read_value(&mut client));
+                client.write_all(b"PANIC\r\n").unwrap();
+                let mut reply = String::new();
+                client.read_to_string(&mut reply).unwrap();
+                assert_eq!("", reply);
+            }
+
+            // the event loop, or the only worker, still serves the other clients
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            client.write_all(b"PING\r\n").unwrap();
+            assert_eq!(Value::Status("OK".to_owned()), read_value(&mut client));
+
+            handle.shutdown().unwrap();
+        }
+    }
+
+    /// Answers `DELAY <ms> <reply>` from another thread, after `ms` milliseconds, and
+    /// `NOW <reply>` deferred but sent right away; closes on `QUIT`
+    struct Delay;
+
+    impl Handler for Delay {
+        fn handle(&mut self, _req: &Value) -> Option<Value> {
+            unreachable!()
+        }
+
+        fn handle_with_ctx(&mut self, ctx: &mut ConnContext, req: &Value) -> Reply {
+            let args = match *req {
+                Value::Bulk(ref args) => args.clone(),
+                _ => return Reply::None,
+            };
+            match args[0].as_slice() {
+                b"DELAY" => {
+                    let ms = args[1].to_string().unwrap().unwrap().parse().unwrap();
+                    let (token, sender) = (ctx.defer(), ctx.reply_sender());
+                    let reply = args[2].clone();
+                    thread::spawn(move || {
+                        thread::sleep(Duration::from_millis(ms));
+                        sender.send(token, reply).unwrap();
+                    });
+                    Reply::Deferred(token)
+                }
+                b"NOW" => {
+                    let token = ctx.defer();
+                    ctx.reply_sender().send(token, args[1].clone()).unwrap();
+                    Reply::Deferred(token)
+                }
+                b"NOREPLY" => Reply::None,
+                b"QUIT" => {
+                    ctx.kill();
+                    Reply::Value(Value::Status("OK".to_owned()))
+                }
+                _ => Reply::Value(args[1].clone()),
+            }
+        }
+    }
+
+    #[test]
+    fn keep_deferred_replies_in_order() {
+        for &workers in [0, 2].iter() {
+            keep_deferred_replies_in_order_with(workers);
+        }
+    }
+
+    fn keep_deferred_replies_in_order_with(workers: usize) {
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .workers(workers)
+            .build(Arc::new(Mutex::new(Delay)))
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        client.write_all(b"DELAY 200 a\r\nECHO b\r\nNOREPLY\r\nDELAY 10 c\r\nECHO d\r\n").unwrap();
+        let mut replies = [0; 28];
+        client.read_exact(&mut replies).unwrap();
+        assert_eq!(&b"$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n"[..], &replies[..]);
+
+        // sent before the worker hands the deferred reply over to the event loop
+        client.write_all(&b"NOW x\r\nECHO y\r\n".repeat(100)).unwrap();
+        let mut replies = [0; 1400];
+        client.read_exact(&mut replies).unwrap();
+        assert_eq!(&b"$1\r\nx\r\n$1\r\ny\r\n".repeat(100)[..], &replies[..]);
+
+        // the reply to a client gone is dropped
+        client.write_all(b"DELAY 100 e\r\n").unwrap();
+        drop(client);
+        thread::sleep(Duration::from_millis(200));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"ECHO f\r\n").unwrap();
+        assert_eq!(Value::Data(b"f".to_vec()), read_value(&mut client));
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn send_deferred_replies_before_closing() {
+        for &workers in [0, 2].iter() {
+            let handle = Server::builder()
+                .bind("127.0.0.1:0")
+                .workers(workers)
+                .build(Arc::new(Mutex::new(Delay)))
+                .unwrap()
+                .start()
+                .unwrap();
+            let addr = handle.local_addr().tcp().unwrap();
+
+            // killed, or failed, while a deferred reply is pending
+            let cases = [
+                (&b"QUIT\r\n"[..], &b"+OK\r\n"[..]),
+                (&b"*1\r\n$x\r\n"[..], &b"-ERR Protocol error: invalid bulk length\r\n"[..]),
+            ];
+            for &(last, reply) in cases.iter() {
+                let mut client = TcpStream::connect(addr).unwrap();
+                client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+                client.write_all(&[&b"DELAY 200 a\r\n"[..], last].concat()).unwrap();
+                let mut replies = Vec::new();
+                client.read_to_end(&mut replies).unwrap();
+                assert_eq!([&b"$1\r\na\r\n"[..], reply].concat(), replies);
+            }
+
+            handle.shutdown().unwrap();
+        }
+    }
+
+    /// Waits on `SLOW` until the gate opens, with a handler per client
+    struct Slow {
+        gate: Arc<AtomicBool>,
+    }
+
+    impl Handler for Slow {
+        fn handle(&mut self, req: &Value) -> Option<Value> {
+            if *req == Value::Bulk(vec![Value::Data(b"SLOW".to_vec())]) {
+                while !self.gate.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(10));
+                }
+            }
+            Some(req.clone())
+        }
+    }
+
+    impl HandlerFactory for Slow {
+        fn new_connection(&self, _ctx: &ConnContext) -> Box<dyn Handler + Send> {
+            Box::new(Slow { gate: self.gate.clone() })
+        }
+    }
+
+    #[test]
+    fn run_handlers_on_workers() {
+        let gate = Arc::new(AtomicBool::new(false));
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .workers(2)
+            .build_with_factory(Slow { gate: gate.clone() })
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        let mut slow = TcpStream::connect(addr).unwrap();
+        let mut fast = TcpStream::connect(addr).unwrap();
+        slow.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        fast.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        slow.write_all(b"SLOW\r\nA\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        fast.write_all(b"B\r\n").unwrap();
+
+        // the fast client does not wait for the slow one, which waits until it is served
+        let mut reply = [0; 11];
+        fast.read_exact(&mut reply).unwrap();
+        assert_eq!(&b"*1\r\n$1\r\nB\r\n"[..], &reply[..]);
+        gate.store(true, Ordering::SeqCst);
+
+        // and the slow one gets its replies in order
+        let mut reply = [0; 25];
+        slow.read_exact(&mut reply).unwrap();
+        assert_eq!(&b"*1\r\n$4\r\nSLOW\r\n*1\r\n$1\r\nA\r\n"[..], &reply[..]);
+
+        handle.shutdown().unwrap();
+    }
+
+    /// Replies with the thread running it and the client id, deferred on `DEFER`
+    struct Where;
+
+    impl Handler for Where {
+        fn handle(&mut self, _req: &Value) -> Option<Value> {
+            None
+        }
+
+        fn handle_with_ctx(&mut self, ctx: &mut ConnContext, req: &Value) -> Reply {
+            let reply = Value::Data(format!("{} {}", thread::current().name().unwrap_or(""), ctx.id()).into_bytes());
+            if *req == Value::Bulk(vec![Value::Data(b"DEFER".to_vec())]) {
+                let (token, sender) = (ctx.defer(), ctx.reply_sender());
+                thread::spawn(move || sender.send(token, reply).unwrap());
+                return Reply::Deferred(token);
+            }
+            Reply::Value(reply)
+        }
+    }
+
+    impl HandlerFactory for Where {
+        fn new_connection(&self, _ctx: &ConnContext) -> Box<dyn Handler + Send> {
+            Box::new(Where)
+        }
+    }
+
+    #[test]
+    fn spread_clients_over_event_loops() {
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .event_loops(3)
+            .max_clients(6)
+            .build_with_factory(Where)
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        let mut clients: Vec<TcpStream> = (0..6).map(|_| TcpStream::connect(addr).unwrap()).collect();
+        let mut threads = Vec::new();
+        let mut ids = Vec::new();
+        for client in clients.iter_mut() {
+            for req in [&b"WHERE\r\n"[..], &b"DEFER\r\n"[..]].iter() {
+                client.write_all(req).unwrap();
+                let reply = match read_value(client) {
+                    Value::Data(data) => String::from_utf8(data).unwrap(),
+                    other => panic!("unexpected reply {:?}", other),
+                };
+                let mut fields = reply.split(' ');
+                let thread = fields.next().unwrap().to_owned();
+                let id = fields.next().unwrap().to_owned();
+                if **req == b"WHERE\r\n"[..] {
+                    threads.push(thread);
+                    ids.push(id);
+                } else {
+                    assert_eq!(ids.last(), Some(&id));
+                }
+            }
+        }
+        // in turn, each with an id of its own
+        assert_eq!(vec!["redif-0", "redif-1", "redif-2", "redif-0", "redif-1", "redif-2"], threads);
+        ids.sort();
+        ids.dedup();
+        assert_eq!(6, ids.len());
+
+        // the limit counts the clients of every event loop
+        let mut refused = TcpStream::connect(addr).unwrap();
+        let mut reply = String::new();
+        refused.read_to_string(&mut reply).unwrap();
+        assert_eq!("-ERR max number of clients reached\r\n", reply);
+
+        // a client gone frees its place
+        drop(clients.pop());
+        thread::sleep(Duration::from_millis(100));
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"WHERE\r\n").unwrap();
+        match read_value(&mut client) {
+            Value::Data(_) => {}
+            other => panic!("unexpected reply {:?}", other),
+        }
+
+        handle.shutdown().unwrap();
+    }
+
+    /// Replies `n` bytes to `BIG n`, and echoes the other requests
+    struct Big;
+
+    impl Handler for Big {
+        fn handle(&mut self, req: &Value) -> Option<Value> {
+            match *req {
+                Value::Bulk(ref args) if args[0] == Value::Data(b"BIG".to_vec()) => {
+                    let n = String::from_utf8_lossy(args[1].as_slice()).parse().unwrap();
+                    Some(Value::Data(vec![b'x'; n]))
+                }
+                _ => Some(req.clone()),
+            }
+        }
+    }
+
+    #[test]
+    fn resume_writing_once_writable() {
+        for &workers in [0, 2].iter() {
+            let handle = Server::builder()
+                .bind("127.0.0.1:0")
+                .workers(workers)
+                .build(Arc::new(Mutex::new(Big)))
+                .unwrap()
+                .start()
+                .unwrap();
+            let addr = handle.local_addr().tcp().unwrap();
+
+            let mut client = TcpStream::connect(addr).unwrap();
+            small_receive_buffer(&client);
+            client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+            // the replies fill the socket buffers up, while the requests keep being read
+            client.write_all(b"BIG 4194304\r\nBIG 4194304\r\n").unwrap();
+            thread::sleep(Duration::from_millis(100));
+            client.write_all(b"END\r\n").unwrap();
+            thread::sleep(Duration::from_millis(100));
+
+            let big = [&b"$4194304\r\n"[..], &vec![b'x'; 4194304], &b"\r\n"[..]].concat();
+            let want = [&big[..], &big[..], &b"*1\r\n$3\r\nEND\r\n"[..]].concat();
+            let mut replies = vec![0; want.len()];
+            client.read_exact(&mut replies).unwrap();
+            assert!(want == replies);
+
+            handle.shutdown().unwrap();
+        }
+    }
+
+    /// Like `Big`, switching to the pubsub client class on `SUBSCRIBE`, and closing on `QUIT`
+    struct Subscriber;
+
+    impl Handler for Subscriber {
+        fn handle(&mut self, req: &Value) -> Option<Value> {
+            Big.handle(req)
+        }
+
+        fn handle_with_ctx(&mut self, ctx: &mut ConnContext, req: &Value) -> Reply {
+            if *req == Value::Bulk(vec![Value::Data(b"SUBSCRIBE".to_vec())]) {
+                ctx.set_class(ClientClass::Pubsub);
+                return Reply::Value(Value::Status("OK".to_owned()));
+            }
+            if *req == Value::Bulk(vec![Value::Data(b"QUIT".to_vec())]) {
+                ctx.kill();
+                return Reply::Value(Value::Status("OK".to_owned()));
+            }
+            self.handle(req).into()
+        }
+    }
+
+    #[test]
+    fn evict_client_over_output_buffer_limit() {
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .output_buffer_limit(ClientClass::Pubsub, OutputBufferLimit::new(1024 * 1024, 0, 0))
+            .build(Arc::new(Mutex::new(Subscriber)))
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        small_receive_buffer(&client);
+        client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+        // no limit for a normal client
+        let big = [&b"$8388608\r\n"[..], &vec![b'x'; 8388608], &b"\r\n"[..]].concat();
+        client.write_all(b"BIG 8388608\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let mut reply = vec![0; big.len()];
+        client.read_exact(&mut reply).unwrap();
+        assert!(big == reply);
+
+        client.write_all(b"SUBSCRIBE\r\n").unwrap();
+        let mut reply = [0; 5];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(b"+OK\r\n", &reply);
+
+        // the rest of the reply is dropped along with the client
+        client.write_all(b"BIG 8388608\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let mut received = 0;
+        let mut buf = [0; 64 * 1024];
+        loop {
+            match client.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => received += n,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => break,
+                Err(e) => panic!("{}", e),
+            }
+        }
+        assert!(received < big.len(), "{}", received);
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn send_replies_before_closing_slow_clients() {
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .build(Arc::new(Mutex::new(Subscriber)))
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+        let big = [&b"$8388608\r\n"[..], &vec![b'x'; 8388608], &b"\r\n"[..]].concat();
+
+        // killed, or failed, with more replies pending than the socket buffers hold
+        let cases = [
+            (&b"QUIT\r\n"[..], &b"+OK\r\n"[..]),
+            (&b"*1\r\n$x\r\n"[..], &b"-ERR Protocol error: invalid bulk length\r\n"[..]),
+        ];
+        for &(last, reply) in cases.iter() {
+            let mut slow = TcpStream::connect(addr).unwrap();
+            small_receive_buffer(&slow);
+            slow.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            slow.write_all(&[&b"BIG 8388608\r\n"[..], last].concat()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+
+            // the event loop serves the other clients meanwhile
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            client.write_all(b"PING\r\n").unwrap();
+            assert_eq!(Value::Bulk(vec![Value::Data(b"PING".to_vec())]), read_value(&mut client));
+
+            // and the slow client gets all of its replies, however late it reads them
+            thread::sleep(Duration::from_millis(1200));
+            let mut replies = Vec::new();
+            slow.read_to_end(&mut replies).unwrap();
+            assert!(replies == [&big[..], reply].concat(), "{}", replies.len());
+        }
+
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn close_clients_not_reading_their_replies() {
+        let builders = [
+            Server::builder().idle_timeout(1),
+            Server::builder().output_buffer_limit(ClientClass::Pubsub, OutputBufferLimit::new(0, 1024 * 1024, 1)),
+        ];
+        for builder in builders.iter() {
+            let handle = builder.clone()
+                .bind("127.0.0.1:0")
+                .build(Arc::new(Mutex::new(Subscriber)))
+                .unwrap()
+                .start()
+                .unwrap();
+            let addr = handle.local_addr().tcp().unwrap();
+
+            let mut slow = TcpStream::connect(addr).unwrap();
+            small_receive_buffer(&slow);
+            slow.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+            slow.write_all(b"SUBSCRIBE\r\n").unwrap();
+            let mut reply = [0; 5];
+            slow.read_exact(&mut reply).unwrap();
+            assert_eq!(b"+OK\r\n", &reply);
+
+            // killed, then closed before its replies are sent
+            slow.write_all(b"BIG 8388608\r\nQUIT\r\n").unwrap();
+            thread::sleep(Duration::from_millis(3500));
+            let mut received = 0;
+            let mut buf = [0; 64 * 1024];
+            loop {
+                match slow.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => received += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => break,
+                    Err(e) => panic!("{}", e),
+                }
+            }
+            assert!(received < 8388608, "{}", received);
+
+            handle.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn shut_down_slow_clients_at_once() {
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .build(Arc::new(Mutex::new(Subscriber)))
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        // none of them reads its replies, flushed one after the other they would take 10 s
+        let clients: Vec<TcpStream> = (0..10).map(|_| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            small_receive_buffer(&client);
+            client.write_all(b"BIG 8388608\r\n").unwrap();
+            client
+        }).collect();
+        thread::sleep(Duration::from_millis(100));
+
+        let start = Instant::now();
+        handle.shutdown().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(5000), "{:?}", start.elapsed());
+        drop(clients);
+    }
+}
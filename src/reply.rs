@@ -0,0 +1,225 @@
+//! Replies of a `Handler`, either at once or deferred
+//!
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::Arc;
+
+use amy::Sender;
+use frame_writer::FrameWriter;
+use value::Value;
+
+/// What the server sends back for a request
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// send the value right away
+    Value(Value),
+    /// send nothing
+    None,
+    /// send the value later given to `ReplySender::send()` with the token
+    Deferred(Token),
+}
+
+impl From<Value> for Reply {
+    fn from(val: Value) -> Reply {
+        Reply::Value(val)
+    }
+}
+
+impl From<Option<Value>> for Reply {
+    fn from(val: Option<Value>) -> Reply {
+        match val {
+            Some(val) => Reply::Value(val),
+            None => Reply::None,
+        }
+    }
+}
+
+/// Identifies a deferred reply, made by `ConnContext::defer()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Token {
+    client: usize,
+    seq: u64,
+}
+
+impl Token {
+    pub(crate) fn new(client: usize, seq: u64) -> Token {
+        Token { client, seq }
+    }
+
+    /// Id of the client to reply to.
+    pub fn client_id(&self) -> usize {
+        self.client
+    }
+}
+
+/// Sends deferred replies from any thread
+///
+/// The replies of a client are sent in the order of its requests: a deferred reply holds
+/// back the replies to the requests pipelined after it, until it is sent.
+#[derive(Debug, Clone)]
+pub struct ReplySender {
+    tx: Arc<Sender<(Token, Value)>>,
+}
+
+impl ReplySender {
+    pub(crate) fn new(tx: Sender<(Token, Value)>) -> ReplySender {
+        ReplySender { tx: Arc::new(tx) }
+    }
+
+    /// Send the reply deferred with `token`.
+    ///
+    /// The reply is dropped if the client is gone; it fails only once the server is stopped.
+    pub fn send(&self, token: Token, reply: Value) -> io::Result<()> {
+        self.tx.send((token, reply))
+            .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, format!("fail to send reply -- {:?}", e)))
+    }
+}
+
+/// Replies of a client held back behind its deferred replies
+pub struct ReplyQueue {
+    /// encoded replies, or none for the deferred ones not sent yet
+    held: VecDeque<Option<Vec<u8>>>,
+    /// number of replies ever taken out of `held`
+    released: u64,
+    /// deferred reply => its position, counted like `released`
+    deferred: HashMap<Token, u64>,
+    /// replies sent before their token came back from a worker, to `defer()`
+    early: HashMap<Token, Vec<u8>>,
+    /// sequence number of the last token deferred
+    last_deferred: u64,
+    /// bytes of the replies held
+    len: usize,
+}
+
+impl ReplyQueue {
+    pub fn new() -> ReplyQueue {
+        ReplyQueue {
+            held: VecDeque::new(),
+            released: 0,
+            deferred: HashMap::new(),
+            early: HashMap::new(),
+            last_deferred: 0,
+            len: 0,
+        }
+    }
+
+    /// Whether the client waits for a deferred reply.
+    pub fn is_waiting(&self) -> bool {
+        !self.held.is_empty()
+    }
+
+    /// Number of bytes of the replies held back.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Queue a reply to `writer`, unless a deferred reply has to go first.
+    pub fn push(&mut self, writer: &mut FrameWriter, data: Vec<u8>) {
+        if self.held.is_empty() {
+            writer.push(data);
+        } else {
+            self.len += data.len();
+            self.held.push_back(Some(data));
+        }
+    }
+
+    /// Keep the place of a deferred reply, or queue it if it was already sent.
+    pub fn defer(&mut self, writer: &mut FrameWriter, token: Token) {
+        self.last_deferred = self.last_deferred.max(token.seq);
+        if let Some(data) = self.early.remove(&token) {
+            self.len -= data.len();
+            self.push(writer, data);
+            return;
+        }
+        self.deferred.insert(token, self.released + self.held.len() as u64);
+        self.held.push_back(None);
+    }
+
+    /// Fill in a deferred reply, and queue to `writer` the replies now in order.
+    ///
+    /// A reply sent before its token is deferred is kept until then. Returns false if the
+    /// reply is not awaited.
+    pub fn resolve(&mut self, writer: &mut FrameWriter, token: Token, data: Vec<u8>) -> bool {
+        let pos = match self.deferred.remove(&token) {
+            Some(pos) => (pos - self.released) as usize,
+            None if token.seq > self.last_deferred => {
+                self.len += data.len();
+                self.early.insert(token, data);
+                return true;
+            }
+            None => return false,
+        };
+        self.len += data.len();
+        self.held[pos] = Some(data);
+        while let Some(&Some(_)) = self.held.front() {
+            if let Some(Some(data)) = self.held.pop_front() {
+                self.len -= data.len();
+                writer.push(data);
+            }
+            self.released += 1;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use frame_writer::FrameWriter;
+    use super::{ReplyQueue, Token};
+
+    /// Sends the frames queued to the writer
+    fn sent(writer: &mut FrameWriter) -> Vec<u8> {
+        let mut out = Vec::new();
+        writer.write(&mut out, None).unwrap();
+        out
+    }
+
+    #[test]
+    fn keep_replies_in_order() {
+        let mut writer = FrameWriter::new();
+        let mut queue = ReplyQueue::new();
+        let (first, second) = (Token::new(1, 1), Token::new(1, 2));
+
+        queue.push(&mut writer, b"a".to_vec());
+        queue.defer(&mut writer, first);
+        queue.push(&mut writer, b"c".to_vec());
+        queue.defer(&mut writer, second);
+        queue.push(&mut writer, b"e".to_vec());
+        assert!(queue.is_waiting());
+        assert_eq!(2, queue.len());
+        assert_eq!(b"a".to_vec(), sent(&mut writer));
+
+        // the second deferred reply waits for the first one
+        assert!(queue.resolve(&mut writer, second, b"d".to_vec()));
+        assert_eq!(Vec::<u8>::new(), sent(&mut writer));
+        assert!(queue.resolve(&mut writer, first, b"b".to_vec()));
+        assert_eq!(b"bcde".to_vec(), sent(&mut writer));
+        assert!(!queue.is_waiting());
+        assert_eq!(0, queue.len());
+
+        assert!(!queue.resolve(&mut writer, first, b"b".to_vec()));
+        queue.push(&mut writer, b"f".to_vec());
+        assert_eq!(b"f".to_vec(), sent(&mut writer));
+    }
+
+    #[test]
+    fn keep_replies_sent_before_deferred() {
+        let mut writer = FrameWriter::new();
+        let mut queue = ReplyQueue::new();
+        let (first, second) = (Token::new(1, 1), Token::new(1, 2));
+
+        // the handler sent the replies before the event loop got its outputs
+        assert!(queue.resolve(&mut writer, second, b"c".to_vec()));
+        assert!(queue.resolve(&mut writer, first, b"a".to_vec()));
+        assert_eq!(2, queue.len());
+        assert_eq!(Vec::<u8>::new(), sent(&mut writer));
+
+        queue.defer(&mut writer, first);
+        queue.push(&mut writer, b"b".to_vec());
+        queue.defer(&mut writer, second);
+        assert!(!queue.is_waiting());
+        assert_eq!(0, queue.len());
+        assert_eq!(b"abc".to_vec(), sent(&mut writer));
+    }
+}
@@ -0,0 +1,574 @@
+//! Server configuration and construction
+//!
+
+use std::collections::HashMap;
+use std::io::{self, Result};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use amy::Sender;
+use context::ClientClass;
+use listener::{Listener, SockAddr};
+use redif;
+use worker::Workers;
+#[cfg(feature = "tls")]
+use tls;
+use {Handler, HandlerFactory};
+
+/// Address listened on when none is configured.
+const DEFAULT_ADDR: &str = "0.0.0.0:6379";
+
+/// An address to listen on
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bind {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+/// Bytes of replies pending for a client beyond which it is disconnected, 0 for no limit
+///
+/// Like Redis `client-output-buffer-limit`: over the hard limit at once, or over the soft
+/// limit for longer than `soft_seconds`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct OutputBufferLimit {
+    pub hard: usize,
+    pub soft: usize,
+    pub soft_seconds: u64,
+}
+
+impl OutputBufferLimit {
+    pub fn new(hard: usize, soft: usize, soft_seconds: u64) -> OutputBufferLimit {
+        OutputBufferLimit { hard, soft, soft_seconds }
+    }
+
+    /// Whether `pending` bytes reach the limit, given since when they reach the soft limit,
+    /// which is updated.
+    pub(crate) fn reached(&self, pending: usize, soft_since: &mut Option<Instant>) -> bool {
+        if self.hard > 0 && pending >= self.hard {
+            return true;
+        }
+        if self.soft == 0 || pending < self.soft {
+            *soft_since = None;
+            return false;
+        }
+        match *soft_since {
+            Some(since) => since.elapsed() > Duration::from_secs(self.soft_seconds),
+            None => {
+                *soft_since = Some(Instant::now());
+                false
+            }
+        }
+    }
+}
+
+/// Settings shared by the listeners and the event loop of a server.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub binds: Vec<Bind>,
+    pub unix_socket_perm: Option<u32>,
+    #[cfg(feature = "tls")]
+    pub tls_cert_file: Option<PathBuf>,
+    #[cfg(feature = "tls")]
+    pub tls_key_file: Option<PathBuf>,
+    pub max_frame_size: u32,
+    #[cfg(feature = "bytes")]
+    pub shared_data: bool,
+    pub poll_timeout: usize,
+    pub max_clients: usize,
+    pub idle_timeout: u64,
+    pub workers: usize,
+    pub event_loops: usize,
+    pub output_buffer_limits: HashMap<ClientClass, OutputBufferLimit>,
+    pub log_target: String,
+}
+
+impl Config {
+    /// Output buffer limit of the clients of `class`, none unless configured.
+    pub fn output_buffer_limit(&self, class: ClientClass) -> OutputBufferLimit {
+        self.output_buffer_limits.get(&class).cloned().unwrap_or_default()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            binds: Vec::new(),
+            unix_socket_perm: None,
+            #[cfg(feature = "tls")]
+            tls_cert_file: None,
+            #[cfg(feature = "tls")]
+            tls_key_file: None,
+            max_frame_size: 1024 * 1024,
+            #[cfg(feature = "bytes")]
+            shared_data: false,
+            poll_timeout: 5000,
+            max_clients: 10000,
+            idle_timeout: 0,
+            workers: 0,
+            event_loops: 1,
+            // the defaults of Redis
+            output_buffer_limits: vec![
+                (ClientClass::Replica, OutputBufferLimit::new(256 * 1024 * 1024, 64 * 1024 * 1024, 60)),
+                (ClientClass::Pubsub, OutputBufferLimit::new(32 * 1024 * 1024, 8 * 1024 * 1024, 60)),
+            ].into_iter().collect(),
+            log_target: "redif".to_owned(),
+        }
+    }
+}
+
+/// Builder of a redif `Server`
+///
+/// ```no_run
+/// # use std::sync::{Arc, Mutex};
+/// # use redif::{Handler, Server, Value};
+/// # struct Echo;
+/// # impl Handler for Echo {
+/// #     fn handle(&mut self, req: &Value) -> Option<Value> { Some(req.clone()) }
+/// # }
+/// let server = Server::builder()
+///     .bind("127.0.0.1:4400")
+///     .max_frame_size(64 * 1024)
+///     .poll_timeout(1000)
+///     .max_clients(128)
+///     .log_target("echo")
+///     .build(Arc::new(Mutex::new(Echo)))
+///     .unwrap();
+///
+/// server.serve().unwrap();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ServerBuilder {
+    config: Config,
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            config: Config::default(),
+        }
+    }
+
+    /// Add a TCP address to listen on, e.g. `"127.0.0.1:6379"` or `"[::1]:6379"`.
+    ///
+    /// A host name resolving to several addresses binds the first one that succeeds.
+    /// Port 0 binds an ephemeral port, see `Server::local_addrs()`.
+    ///
+    /// A server without any `bind()` nor `bind_unix()` listens on `"0.0.0.0:6379"`.
+    pub fn bind<S: Into<String>>(mut self, addr: S) -> ServerBuilder {
+        self.config.binds.push(Bind::Tcp(addr.into()));
+        self
+    }
+
+    /// Add the path of a Unix domain socket to listen on.
+    ///
+    /// A stale socket file left at `path` by a dead server is replaced, and the socket file is
+    /// removed when the server stops.
+    pub fn bind_unix<P: AsRef<Path>>(mut self, path: P) -> ServerBuilder {
+        self.config.binds.push(Bind::Unix(path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// File permissions of the Unix domain sockets, e.g. `0o700` (default per umask).
+    pub fn unix_socket_perm(mut self, mode: u32) -> ServerBuilder {
+        self.config.unix_socket_perm = Some(mode);
+        self
+    }
+
+    /// Serve the clients of every TCP address over TLS, with the PEM encoded certificate chain and private key files.
+    #[cfg(feature = "tls")]
+    pub fn tls<P: AsRef<Path>, Q: AsRef<Path>>(mut self, cert_file: P, key_file: Q) -> ServerBuilder {
+        self.config.tls_cert_file = Some(cert_file.as_ref().to_path_buf());
+        self.config.tls_key_file = Some(key_file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Size in bytes of the largest request a client may send (default 1 MiB).
+    pub fn max_frame_size(mut self, size: u32) -> ServerBuilder {
+        self.config.max_frame_size = size;
+        self
+    }
+
+    /// Decode bulk strings as `Value::Shared` slices of the read buffers rather than copies in
+    /// `Value::Data` (default false).
+    ///
+    /// Handlers keeping a request argument then hold on to the buffer it was read into, for as
+    /// long as they keep it.
+    #[cfg(feature = "bytes")]
+    pub fn shared_data(mut self, shared: bool) -> ServerBuilder {
+        self.config.shared_data = shared;
+        self
+    }
+
+    /// Milliseconds the event loop waits for socket events in one round (default 5000).
+    pub fn poll_timeout(mut self, timeout_ms: usize) -> ServerBuilder {
+        self.config.poll_timeout = timeout_ms;
+        self
+    }
+
+    /// Number of simultaneous clients, beyond which new connections are refused (default 10000).
+    pub fn max_clients(mut self, max_clients: usize) -> ServerBuilder {
+        self.config.max_clients = max_clients;
+        self
+    }
+
+    /// Seconds a client may stay idle, neither sending requests nor reading its replies, before
+    /// it is disconnected, 0 for never (default 0).
+    pub fn idle_timeout(mut self, timeout_secs: u64) -> ServerBuilder {
+        self.config.idle_timeout = timeout_secs;
+        self
+    }
+
+    /// Number of threads calling the handlers, while the event loop thread keeps doing the I/O
+    /// (default 0, the event loop thread calls them).
+    ///
+    /// Each client still gets its requests handled one after the other, and its replies in order.
+    pub fn workers(mut self, count: usize) -> ServerBuilder {
+        self.config.workers = count;
+        self
+    }
+
+    /// Number of event loop threads, each with its own poller and clients (default 1).
+    ///
+    /// The first one accepts the clients, and hands them off to all of them in turn.
+    pub fn event_loops(mut self, count: usize) -> ServerBuilder {
+        self.config.event_loops = count;
+        self
+    }
+
+    /// Output buffer limit of the clients of `class` (default none for `Normal`, 256/64 MiB
+    /// over 60 s for `Replica`, 32/8 MiB over 60 s for `Pubsub`, like Redis).
+    pub fn output_buffer_limit(mut self, class: ClientClass, limit: OutputBufferLimit) -> ServerBuilder {
+        self.config.output_buffer_limits.insert(class, limit);
+        self
+    }
+
+    /// Target of the log records emitted by the server (default `"redif"`).
+    pub fn log_target<S: Into<String>>(mut self, target: S) -> ServerBuilder {
+        self.config.log_target = target.into();
+        self
+    }
+
+    /// Bind the listeners and return a server ready to `serve()` requests with `handler`.
+    pub fn build<T: Send + Handler + 'static>(self, handler: Arc<Mutex<T>>) -> Result<Server> {
+        self.build_with_factory(handler)
+    }
+
+    /// Bind the listeners and return a server handling the requests of each client with
+    /// its own handler, made by `factory`.
+    pub fn build_with_factory<F: HandlerFactory + Send + Sync + 'static>(self, factory: F) -> Result<Server> {
+        if self.config.max_frame_size == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "max_frame_size must be positive"));
+        }
+        if self.config.event_loops == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "event_loops must be positive"));
+        }
+
+        let mut binds = self.config.binds.clone();
+        if binds.is_empty() {
+            binds.push(Bind::Tcp(DEFAULT_ADDR.to_owned()));
+        }
+
+        let mut listeners = Vec::with_capacity(binds.len());
+        for bind in &binds {
+            let listener = match *bind {
+                Bind::Tcp(ref addr) => self.bind_tcp(addr)?,
+                Bind::Unix(ref path) => Listener::bind_unix(path, self.config.unix_socket_perm)?,
+            };
+            listener.set_nonblocking(true)?;
+
+            info!(target: &self.config.log_target, "Listening on {} ...", listener.local_addr()?);
+            listeners.push(listener);
+        }
+
+        Ok(Server {
+            config: self.config,
+            listeners,
+            factory: Arc::new(factory),
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn bind_tcp(&self, addr: &str) -> Result<Listener> {
+        Listener::bind_tcp(addr)
+    }
+
+    #[cfg(feature = "tls")]
+    fn bind_tcp(&self, addr: &str) -> Result<Listener> {
+        match (&self.config.tls_cert_file, &self.config.tls_key_file) {
+            (Some(cert), Some(key)) => Listener::bind_tls(addr, tls::load_config(cert, key)?),
+            _ => Listener::bind_tcp(addr),
+        }
+    }
+}
+
+/// A bound redif server
+///
+/// All its listeners share one connection table and one handler factory.
+pub struct Server {
+    config: Config,
+    listeners: Vec<Listener>,
+    factory: Arc<dyn HandlerFactory + Send + Sync>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    /// The address of the first listener, with the actual port when bound to port 0.
+    pub fn local_addr(&self) -> Result<SockAddr> {
+        self.listeners[0].local_addr()
+    }
+
+    /// The addresses of all listeners, in the order they were configured.
+    pub fn local_addrs(&self) -> Result<Vec<SockAddr>> {
+        self.listeners.iter().map(|listener| listener.local_addr()).collect()
+    }
+
+    /// Accept clients and dispatch their requests to the handler, blocking the calling thread.
+    pub fn serve(self) -> Result<()> {
+        self.start()?.join()
+    }
+
+    /// Accept clients and dispatch their requests to the handler in background threads.
+    ///
+    /// The returned handle stops the server with `shutdown()`.
+    pub fn start(self) -> Result<ServerHandle> {
+        redif::start(self.config, self.listeners, self.factory)
+    }
+}
+
+/// Handle to a running redif server
+///
+/// On `shutdown()` the server stops accepting clients, flushes the replies still pending for
+/// every connection for a second at most, closes them, and joins its threads.
+pub struct ServerHandle {
+    local_addrs: Vec<SockAddr>,
+    stopped: Arc<AtomicBool>,
+    /// one per event loop
+    wakeups: Vec<Sender<()>>,
+    threads: Vec<JoinHandle<()>>,
+    /// joined once the event loops are stopped
+    workers: Option<Workers>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(local_addrs: Vec<SockAddr>, stopped: Arc<AtomicBool>, wakeups: Vec<Sender<()>>, threads: Vec<JoinHandle<()>>, workers: Option<Workers>) -> ServerHandle {
+        ServerHandle {
+            local_addrs,
+            stopped,
+            wakeups,
+            threads,
+            workers,
+        }
+    }
+
+    /// The address of the first listener.
+    pub fn local_addr(&self) -> SockAddr {
+        self.local_addrs[0].clone()
+    }
+
+    /// The addresses of all listeners, in the order they were configured.
+    pub fn local_addrs(&self) -> &[SockAddr] {
+        &self.local_addrs
+    }
+
+    /// Stop the server and wait until every connection is closed.
+    pub fn shutdown(self) -> Result<()> {
+        self.stopped.store(true, Ordering::SeqCst);
+        for wakeup in &self.wakeups {
+            wakeup.send(())
+                .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, format!("fail to wake up server -- {:?}", e)))?;
+        }
+        self.join()
+    }
+
+    /// Block until the server threads exit.
+    pub fn join(self) -> Result<()> {
+        for handle in self.threads {
+            handle.join()
+                .map_err(|_| io::Error::other("server thread panicked"))?;
+        }
+        if let Some(workers) = self.workers {
+            workers.join();
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::env;
+    use std::net::TcpStream;
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use super::{Bind, OutputBufferLimit, ServerBuilder};
+    use super::super::{ClientClass, Handler, Value, encode_slice};
+
+    struct Nop;
+
+    impl Handler for Nop {
+        fn handle(&mut self, _req: &Value) -> Option<Value> {
+            None
+        }
+    }
+
+    struct Counter {
+        requests: usize,
+    }
+
+    impl Handler for Counter {
+        fn handle(&mut self, _req: &Value) -> Option<Value> {
+            self.requests += 1;
+            Some(Value::Int(self.requests as i64))
+        }
+    }
+
+    #[test]
+    fn builder_defaults() {
+        let builder = ServerBuilder::new();
+        assert!(builder.config.binds.is_empty());
+        assert_eq!(1024 * 1024, builder.config.max_frame_size);
+        assert_eq!(5000, builder.config.poll_timeout);
+        assert_eq!(10000, builder.config.max_clients);
+        assert_eq!(OutputBufferLimit::default(), builder.config.output_buffer_limit(ClientClass::Normal));
+        assert_eq!(OutputBufferLimit::new(32 * 1024 * 1024, 8 * 1024 * 1024, 60), builder.config.output_buffer_limit(ClientClass::Pubsub));
+        assert_eq!("redif", builder.config.log_target);
+    }
+
+    #[test]
+    fn builder_overrides() {
+        let builder = ServerBuilder::new()
+            .bind("127.0.0.1:4400")
+            .max_frame_size(512)
+            .poll_timeout(10)
+            .max_clients(2)
+            .log_target("test");
+        assert_eq!(vec![Bind::Tcp("127.0.0.1:4400".to_owned())], builder.config.binds);
+        assert_eq!(512, builder.config.max_frame_size);
+        assert_eq!(10, builder.config.poll_timeout);
+        assert_eq!(2, builder.config.max_clients);
+        assert_eq!("test", builder.config.log_target);
+    }
+
+    #[test]
+    fn reach_output_buffer_limit() {
+        let mut since = None;
+        assert!(!OutputBufferLimit::default().reached(usize::MAX, &mut since));
+
+        let limit = OutputBufferLimit::new(100, 10, 1);
+        assert!(limit.reached(100, &mut since));
+
+        // over the soft limit for too long
+        assert!(!limit.reached(10, &mut since));
+        assert!(since.is_some());
+        assert!(!limit.reached(50, &mut since));
+        since = Some(Instant::now() - Duration::from_secs(2));
+        assert!(limit.reached(10, &mut since));
+
+        // back under it
+        assert!(!limit.reached(9, &mut since));
+        assert!(since.is_none());
+    }
+
+    #[test]
+    fn build_rejects_bad_config() {
+        let handler = Arc::new(Mutex::new(Nop));
+        assert!(ServerBuilder::new().bind("not an address").build(handler.clone()).is_err());
+        assert!(ServerBuilder::new().bind("127.0.0.1:0").max_frame_size(0).build(handler.clone()).is_err());
+        assert!(ServerBuilder::new().bind("127.0.0.1:0").event_loops(0).build(handler).is_err());
+    }
+
+    #[test]
+    fn bind_ephemeral_port() {
+        let handler = Arc::new(Mutex::new(Nop));
+        let server = ServerBuilder::new().bind("127.0.0.1:0").build(handler).unwrap();
+        let addr = server.local_addr().unwrap().tcp().unwrap();
+        assert!(addr.port() != 0);
+
+        let handle = server.start().unwrap();
+        assert_eq!(Some(addr), handle.local_addr().tcp());
+        assert!(TcpStream::connect(addr).is_ok());
+        handle.shutdown().unwrap();
+    }
+
+    #[test]
+    fn shutdown_closes_connections() {
+        let handler = Arc::new(Mutex::new(Counter { requests: 0 }));
+        let server = ServerBuilder::new().bind("127.0.0.1:0").poll_timeout(100).build(handler.clone()).unwrap();
+        let handle = server.start().unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&encode_slice(&["INCR", "requests"])).unwrap();
+        let mut reply = [0; 4];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(b":1\r\n", &reply);
+
+        handle.shutdown().unwrap();
+
+        // the server closed the connection and stopped listening
+        assert_eq!(0, client.read(&mut reply).unwrap());
+        assert!(TcpStream::connect(addr).is_err());
+        assert_eq!(1, handler.lock().unwrap().requests);
+    }
+
+    #[test]
+    fn serve_unix_socket() {
+        let path = env::temp_dir().join(format!("redif-server-{}.sock", ::std::process::id()));
+        let handler = Arc::new(Mutex::new(Counter { requests: 0 }));
+        let server = ServerBuilder::new().bind_unix(&path).unix_socket_perm(0o700).build(handler).unwrap();
+        let handle = server.start().unwrap();
+        assert_eq!(Some(path.as_path()), handle.local_addr().unix_path());
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(&encode_slice(&["INCR", "requests"])).unwrap();
+        let mut reply = [0; 4];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(b":1\r\n", &reply);
+
+        handle.shutdown().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn serve_several_listeners() {
+        let path = env::temp_dir().join(format!("redif-listeners-{}.sock", ::std::process::id()));
+        let handler = Arc::new(Mutex::new(Counter { requests: 0 }));
+        let server = ServerBuilder::new()
+            .bind("127.0.0.1:0")
+            .bind("[::1]:0")
+            .bind_unix(&path)
+            .build(handler.clone())
+            .unwrap();
+        let handle = server.start().unwrap();
+        let addrs = handle.local_addrs().to_vec();
+        assert_eq!(3, addrs.len());
+        assert!(addrs[0].tcp().unwrap().is_ipv4());
+        assert!(addrs[1].tcp().unwrap().is_ipv6());
+
+        let mut clients: Vec<Box<dyn ReadWrite>> = vec![
+            Box::new(TcpStream::connect(addrs[0].tcp().unwrap()).unwrap()),
+            Box::new(TcpStream::connect(addrs[1].tcp().unwrap()).unwrap()),
+            Box::new(UnixStream::connect(addrs[2].unix_path().unwrap()).unwrap()),
+        ];
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.write_all(&encode_slice(&["INCR", "requests"])).unwrap();
+            let mut reply = [0; 4];
+            client.read_exact(&mut reply).unwrap();
+            assert_eq!(format!(":{}\r\n", i + 1).as_bytes(), &reply);
+        }
+
+        handle.shutdown().unwrap();
+        assert_eq!(3, handler.lock().unwrap().requests);
+    }
+
+    trait ReadWrite: Read + Write {}
+    impl<T: Read + Write> ReadWrite for T {}
+}
@@ -0,0 +1,203 @@
+//! TLS sessions over non-blocking TCP streams
+//!
+
+use std::error::Error;
+use std::io::{self, IoSlice, Read, Write, Result};
+use std::net::{TcpStream, Shutdown};
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::{ServerConfig, ServerConnection};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::pki_types::pem::PemObject;
+
+/// Load a PEM certificate chain and private key into a TLS server configuration.
+pub fn load_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<::std::result::Result<Vec<_>, _>>())
+        .map_err(|e| invalid_data(format!("fail to load certificate {} -- {}", cert.display(), e)))?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| invalid_data(format!("fail to load private key {} -- {}", key.display(), e)))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(invalid_data)?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(invalid_data)?;
+
+    Ok(Arc::new(config))
+}
+
+fn invalid_data<E: Into<Box<dyn Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// A server side TLS session over a non-blocking TCP stream
+///
+/// Reads and writes carry plaintext, and return `WouldBlock` like the underlying socket does.
+/// Encrypted records that could not be sent yet are kept by the session until `flush()`.
+#[derive(Debug)]
+pub struct TlsStream {
+    sock: TcpStream,
+    session: ServerConnection,
+}
+
+impl TlsStream {
+    pub fn new(sock: TcpStream, config: Arc<ServerConfig>) -> Result<TlsStream> {
+        let session = ServerConnection::new(config).map_err(invalid_data)?;
+        Ok(TlsStream {
+            sock,
+            session,
+        })
+    }
+
+    pub fn get_ref(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> Result<()> {
+        self.session.send_close_notify();
+        let _ = self.write_tls();
+        self.sock.shutdown(how)
+    }
+
+    /// Send the pending encrypted records, `WouldBlock` if some are left.
+    fn write_tls(&mut self) -> Result<()> {
+        while self.session.wants_write() {
+            if self.session.write_tls(&mut self.sock)? == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "fail to write TLS records"));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.session.reader().read(buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            }
+
+            if self.session.read_tls(&mut self.sock)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = self.session.process_new_packets() {
+                // let the peer know about the failure before the connection is dropped
+                let _ = self.write_tls();
+                return Err(invalid_data(e));
+            }
+
+            // answer the handshake messages just received
+            match self.write_tls() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => res?,
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // hold new data back until the records of earlier writes are out
+        self.write_tls()?;
+        let n = self.session.writer().write(buf)?;
+        match self.write_tls() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(n),
+            res => res.map(|_| n),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> Result<usize> {
+        self.write_tls()?;
+        let n = self.session.writer().write_vectored(bufs)?;
+        match self.write_tls() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(n),
+            res => res.map(|_| n),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.session.writer().flush()?;
+        self.write_tls()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    extern crate rcgen;
+
+    use std::convert::TryFrom;
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use rustls::pki_types::ServerName;
+    use super::super::{Handler, Server, Value, encode_slice};
+
+    struct Echo;
+
+    impl Handler for Echo {
+        fn handle(&mut self, req: &Value) -> Option<Value> {
+            Some(req.clone())
+        }
+    }
+
+    #[test]
+    fn serve_over_tls() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let dir = env::temp_dir();
+        let cert_file = dir.join(format!("redif-tls-{}.crt", ::std::process::id()));
+        let key_file = dir.join(format!("redif-tls-{}.key", ::std::process::id()));
+        fs::write(&cert_file, certified.cert.pem()).unwrap();
+        fs::write(&key_file, certified.key_pair.serialize_pem()).unwrap();
+
+        let handle = Server::builder()
+            .bind("127.0.0.1:0")
+            .tls(&cert_file, &key_file)
+            .build(Arc::new(Mutex::new(Echo)))
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = handle.local_addr().tcp().unwrap();
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(::rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let session = ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+        let mut client = StreamOwned::new(session, TcpStream::connect(addr).unwrap());
+
+        let req = encode_slice(&["ECHO", "hello"]);
+        client.write_all(&req).unwrap();
+        let mut reply = vec![0; req.len()];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(req, reply);
+
+        handle.shutdown().unwrap();
+        fs::remove_file(&cert_file).unwrap();
+        fs::remove_file(&key_file).unwrap();
+    }
+
+    #[test]
+    fn reject_bad_certificate() {
+        let cert_file = env::temp_dir().join(format!("redif-tls-bad-{}.crt", ::std::process::id()));
+        fs::write(&cert_file, "not a certificate").unwrap();
+
+        let server = Server::builder()
+            .bind("127.0.0.1:0")
+            .tls(&cert_file, &cert_file)
+            .build(Arc::new(Mutex::new(Echo)));
+        assert!(server.is_err());
+        fs::remove_file(&cert_file).unwrap();
+    }
+}
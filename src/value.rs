@@ -1,12 +1,23 @@
 
-use std::io::Result;
-use std::io;
 use std::fmt;
+use std::io::{self, Write};
 use std::str;
 
+#[cfg(feature = "bytes")]
+use bytes::Bytes;
+
+use decoder::Decoder;
+use error::Result;
+
 
 /// Represents a RESP value, see [Redis Protocol specification](http://redis.io/topics/protocol).
-#[derive(PartialEq, Eq, Clone)]
+///
+/// The RESP3 values are downgraded by `into_resp2()` for the clients which did not send `HELLO 3`.
+///
+/// It is `non_exhaustive`, as features add variants, e.g. `Shared` with `bytes`: a `match` on
+/// a `Value` outside of redif needs a wildcard arm.
+#[derive(Clone)]
+#[non_exhaustive]
 pub enum Value {
     /// A nil response, `$-1\r\n`
     Nil,
@@ -21,6 +32,11 @@ pub enum Value {
     /// An arbitary binary data.
     /// With the first byte of the response is "$".
     Data(Vec<u8>),
+    /// Binary data sharing the buffer it was read into, decoded in place of `Data` by servers
+    /// built with `shared_data(true)`. Cloning it does not copy the data, and it equals the
+    /// `Data` of the same bytes.
+    #[cfg(feature = "bytes")]
+    Shared(Bytes),
     /// A bulk response of more data.  This is generally used by redis
     /// to express nested structures.
     /// With the first byte of the response is "*".
@@ -31,149 +47,218 @@ pub enum Value {
     //Okay,
     /// An error response with the first byte of the response is "-".
     Error(String),
+    /// A RESP3 null, `_\r\n`
+    Null,
+    /// A RESP3 floating point number, with the first byte ",".
+    Double(f64),
+    /// A RESP3 boolean, `#t\r\n` or `#f\r\n`
+    Boolean(bool),
+    /// A RESP3 integer of any size in decimal, with the first byte "(".
+    BigNumber(String),
+    /// A RESP3 binary safe error, with the first byte "!".
+    BlobError(Vec<u8>),
+    /// A RESP3 string along with its format of 3 characters, e.g. `txt` or `mkd`.
+    /// With the first byte "=".
+    Verbatim(String, Vec<u8>),
+    /// A RESP3 map of key and value pairs, with the first byte "%".
+    Map(Vec<(Value, Value)>),
+    /// A RESP3 set, with the first byte "~".
+    Set(Vec<Value>),
+    /// A RESP3 value with auxiliary attributes, with the first byte "|" before the value.
+    Attribute(Vec<(Value, Value)>, Box<Value>),
+    /// A RESP3 out of band data, e.g. a pub/sub message, with the first byte ">".
+    Push(Vec<Value>),
 }
 
+/// Doubles are equal when their bits are, so that `Value` is `Eq`: NaN equals itself, and
+/// `0.0` differs from `-0.0`. `Data` and `Shared` are equal when their bytes are.
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::NullArray, Value::NullArray) => true,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Data(a), Value::Data(b)) => a == b,
+            #[cfg(feature = "bytes")]
+            (Value::Shared(a), Value::Shared(b)) => a == b,
+            #[cfg(feature = "bytes")]
+            (Value::Data(a), Value::Shared(b)) | (Value::Shared(b), Value::Data(a)) => a[..] == b[..],
+            (Value::Bulk(a), Value::Bulk(b)) => a == b,
+            (Value::Status(a), Value::Status(b)) => a == b,
+            (Value::Error(a), Value::Error(b)) => a == b,
+            (Value::Null, Value::Null) => true,
+            (Value::Double(a), Value::Double(b)) => a.to_bits() == b.to_bits(),
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::BigNumber(a), Value::BigNumber(b)) => a == b,
+            (Value::BlobError(a), Value::BlobError(b)) => a == b,
+            (Value::Verbatim(a, x), Value::Verbatim(b, y)) => a == b && x == y,
+            (Value::Map(a), Value::Map(b)) => a == b,
+            (Value::Set(a), Value::Set(b)) => a == b,
+            (Value::Attribute(a, x), Value::Attribute(b, y)) => a == b && x == y,
+            (Value::Push(a), Value::Push(b)) => a == b,
+            _ => false,
+        }
+    }
+}
 
-impl Value {
+impl Eq for Value {}
 
-    const RESP_MAX_SIZE: i64 = 512 * 1024 * 1024;
+/// Version of the protocol spoken with a client, RESP2 until it sends `HELLO 3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
 
-    pub fn decode(bytes: &[u8], start_index: usize) -> Result<(Value,usize)> {
-        let len = bytes.len();
-        let mut k = start_index;
-        while (k < len) && (bytes[k] != b'\n') { 
-            k += 1; 
-        }
-        if k >= len {
-            return Ok((Value::Nil, 0));
-        }
-        if bytes[k - 1] != b'\r' {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid CRLF: {:?}", &bytes[start_index .. k+1])));
-        }
 
-        let p = start_index + 1;
-        let q = k - 1;
-        match bytes[start_index] {
-            // Value::Status
-            b'+' => {
-                match String::from_utf8(bytes[p .. q].to_vec()) {
-                    Ok(s) => return Ok((Value::Status(s), k + 1)),
-                    Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid Data: {:?} -- {}", &bytes[start_index .. k+1], e))),
-                }
-            }
-            // Value::Error
-            b'-' => {
-                match String::from_utf8(bytes[p .. q].to_vec()) {
-                    Ok(s) => return Ok((Value::Error(s), k + 1)),
-                    Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid Data: {:?} -- {}", &bytes[start_index .. k+1], e))),
-                }
-            }
-            // Value::Int
-            b':' => {
-                match String::from_utf8(bytes[p .. q].to_vec()) {
-                    Ok(s) => match s.parse::<i64>() {
-                        Ok(x) => return Ok((Value::Int(x), k + 1)),
-                        Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid Data: {:?} -- {}", &bytes[start_index .. k+1], e))),
-                    }
-                    Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid Data: {:?} -- {}", &bytes[start_index .. k+1], e))),
-                }
-            }
-            // Value::Data
-            b'$' => {
-                let x = parse_length( &bytes[p .. q ] )?;
-                if x == -1 {
-                    return Ok((Value::Nil, k + 1));
-                }
-                if (x < -1) || (x > Self::RESP_MAX_SIZE) {
-                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid Data length: {:?}", &bytes[start_index .. k+1])));
-                }
-                let n = x as usize;
-                if (len - k) >= (n + 2) {
-                    return Ok((Value::Data(bytes[k+1 .. k+n+1].to_vec()), k + n + 2 + 1));
-                }
-            }
-            // Value::Bulk
-            b'*' => {
-                let x = parse_length( &bytes[p .. q ] )?;
-                if x == -1 {
-                    return Ok((Value::NullArray, k + 1));
-                }
-                if (x < -1) || (x > Self::RESP_MAX_SIZE) {
-                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid Array length: {:?}", &bytes[start_index .. k+1])));
-                }
-                let n = x as usize;
-                let mut array: Vec<Value> = Vec::with_capacity(n);
-                let mut offset = k + 1;
-                for _ in 0 .. n {
-                    let (val, _offset) = Self::decode(bytes, offset)?;
-                    if _offset == 0 {
-                        return Ok((Value::Nil, 0));
-                    }
+impl Value {
 
-                    offset = _offset;
-                    array.push( val );
-                }
-                return Ok((Value::Bulk(array), offset));
-            }
-            // invalid prefix
-            prefix => {
-                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid RESP type: {:?}", prefix)));
-            }
-        }
+    const RESP_MAX_SIZE: usize = 512 * 1024 * 1024;
 
-        Ok((Value::Nil, 0))
+    /// Decodes the value starting at `start_index`.
+    ///
+    /// Returns the value and the offset following it, or an offset of 0 if `bytes` holds only
+    /// part of the value.
+    pub fn decode(bytes: &[u8], start_index: usize) -> Result<(Value,usize)> {
+        let mut decoder = Decoder::new(Self::RESP_MAX_SIZE);
+        match decoder.decode(&bytes[start_index ..])? {
+            (Some(value), consumed) => Ok((value, start_index + consumed)),
+            (None, _) => Ok((Value::Nil, 0)),
+        }
     }   //// decode()
 
 
     const CRLF_BYTES: &'static [u8] = b"\r\n";
     const NULL_BYTES: &'static [u8] = b"$-1\r\n";
     const NULL_ARRAY_BYTES: &'static [u8] = b"*-1\r\n";
+    const NULL_RESP3_BYTES: &'static [u8] = b"_\r\n";
 
+    /// Encodes the value in a new buffer of the right size.
     #[inline]
     pub fn encode(&self) -> Vec<u8> {
-        let mut buf = Vec::new();
+        let mut buf = Vec::with_capacity(self.encoded_len());
+        self.write_to(&mut buf).expect("writing to a Vec never fails");
+        buf
+    }   //// encode()
 
+    /// Appends the encoded value to `buf`.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.reserve(self.encoded_len());
+        self.write_to(buf).expect("writing to a Vec never fails");
+    }
+
+    /// Writes the encoded value piece by piece, better to a buffered writer.
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
         match *self {
-            Value::Nil => {
-                buf.extend_from_slice(Self::NULL_BYTES);
-            }
-            Value::NullArray => {
-                buf.extend_from_slice(Self::NULL_ARRAY_BYTES);
+            Value::Nil => w.write_all(Self::NULL_BYTES),
+            Value::NullArray => w.write_all(Self::NULL_ARRAY_BYTES),
+            Value::Status(ref val) => write!(w, "+{}\r\n", val),
+            Value::Error(ref val) => write!(w, "-{}\r\n", val),
+            Value::Int(val) => write!(w, ":{}\r\n", val),
+            Value::Data(ref val) => Self::write_blob(w, b'$', val),
+            #[cfg(feature = "bytes")]
+            Value::Shared(ref val) => Self::write_blob(w, b'$', val),
+            Value::Bulk(ref val) => Self::write_aggregate(w, b'*', val),
+            Value::Null => w.write_all(Self::NULL_RESP3_BYTES),
+            Value::Double(val) => write!(w, ",{}\r\n", Double(val)),
+            Value::Boolean(val) => write!(w, "#{}\r\n", if val { 't' } else { 'f' }),
+            Value::BigNumber(ref val) => write!(w, "({}\r\n", val),
+            Value::BlobError(ref val) => Self::write_blob(w, b'!', val),
+            Value::Verbatim(ref format, ref val) => {
+                write!(w, "={}\r\n{}:", format.len() + 1 + val.len(), format)?;
+                w.write_all(val)?;
+                w.write_all(Self::CRLF_BYTES)
             }
-            Value::Status(ref val) => {
-                buf.push(b'+');
-                buf.extend_from_slice(val.as_bytes());
-                buf.extend_from_slice(Self::CRLF_BYTES);
-            }
-            Value::Error(ref val) => {
-                buf.push(b'-');
-                buf.extend_from_slice(val.as_bytes());
-                buf.extend_from_slice(Self::CRLF_BYTES);
+            Value::Map(ref pairs) => Self::write_pairs(w, b'%', pairs),
+            Value::Set(ref val) => Self::write_aggregate(w, b'~', val),
+            Value::Attribute(ref pairs, ref val) => {
+                Self::write_pairs(w, b'|', pairs)?;
+                val.write_to(w)
             }
-            Value::Int(ref val) => {
-                buf.push(b':');
-                buf.extend_from_slice(val.to_string().as_bytes());
-                buf.extend_from_slice(Self::CRLF_BYTES);
-            }
-            Value::Data(ref val) => {
-                buf.push(b'$');
-                buf.extend_from_slice(val.len().to_string().as_bytes());
-                buf.extend_from_slice(Self::CRLF_BYTES);
-                buf.extend_from_slice(val);
-                buf.extend_from_slice(Self::CRLF_BYTES);
+            Value::Push(ref val) => Self::write_aggregate(w, b'>', val),
+        }
+    }
+
+    fn write_blob<W: Write>(w: &mut W, prefix: u8, val: &[u8]) -> io::Result<()> {
+        write!(w, "{}{}\r\n", prefix as char, val.len())?;
+        w.write_all(val)?;
+        w.write_all(Self::CRLF_BYTES)
+    }
+
+    fn write_aggregate<W: Write>(w: &mut W, prefix: u8, items: &[Value]) -> io::Result<()> {
+        write!(w, "{}{}\r\n", prefix as char, items.len())?;
+        for item in items {
+            item.write_to(w)?;
+        }
+        Ok(())
+    }
+
+    fn write_pairs<W: Write>(w: &mut W, prefix: u8, pairs: &[(Value, Value)]) -> io::Result<()> {
+        write!(w, "{}{}\r\n", prefix as char, pairs.len())?;
+        for (key, val) in pairs {
+            key.write_to(w)?;
+            val.write_to(w)?;
+        }
+        Ok(())
+    }
+
+    /// Length in bytes of the encoded value.
+    pub fn encoded_len(&self) -> usize {
+        let crlf = Self::CRLF_BYTES.len();
+        let blob_len = |len: usize| 1 + decimal_len(len as i64) + crlf + len + crlf;
+        let aggregate_len = |len: usize| 1 + decimal_len(len as i64) + crlf;
+        let pairs_len = |pairs: &[(Value, Value)]| {
+            aggregate_len(pairs.len()) + pairs.iter().map(|(key, val)| key.encoded_len() + val.encoded_len()).sum::<usize>()
+        };
+        match *self {
+            Value::Nil => Self::NULL_BYTES.len(),
+            Value::NullArray => Self::NULL_ARRAY_BYTES.len(),
+            Value::Status(ref val) | Value::Error(ref val) | Value::BigNumber(ref val) => 1 + val.len() + crlf,
+            Value::Int(val) => 1 + decimal_len(val) + crlf,
+            Value::Data(ref val) | Value::BlobError(ref val) => blob_len(val.len()),
+            #[cfg(feature = "bytes")]
+            Value::Shared(ref val) => blob_len(val.len()),
+            Value::Bulk(ref val) | Value::Set(ref val) | Value::Push(ref val) => {
+                aggregate_len(val.len()) + val.iter().map(Value::encoded_len).sum::<usize>()
             }
-            Value::Bulk(ref val) => {
-                buf.push(b'*');
-                buf.extend_from_slice(val.len().to_string().as_bytes());
-                buf.extend_from_slice(Self::CRLF_BYTES);
-                for item in val {
-                    buf.append(&mut item.encode());
+            Value::Null => Self::NULL_RESP3_BYTES.len(),
+            Value::Double(val) => 1 + display_len(Double(val)) + crlf,
+            Value::Boolean(_) => 2 + crlf,
+            Value::Verbatim(ref format, ref val) => blob_len(format.len() + 1 + val.len()),
+            Value::Map(ref pairs) => pairs_len(pairs),
+            Value::Attribute(ref pairs, ref val) => pairs_len(pairs) + val.encoded_len(),
+        }
+    }
+
+    /// Downgrades the RESP3 values for a RESP2 client, the way Redis does.
+    ///
+    /// Null becomes nil, a boolean an integer, a double, big number or verbatim string becomes
+    /// binary data, a map becomes an array of keys and values, a set or push an array, and the
+    /// attributes are dropped.
+    pub fn into_resp2(self) -> Value {
+        match self {
+            Value::Null => Value::Nil,
+            Value::Double(val) => Value::Data(Double(val).to_string().into_bytes()),
+            Value::Boolean(val) => Value::Int(val as i64),
+            Value::BigNumber(val) => Value::Data(val.into_bytes()),
+            Value::BlobError(val) => Value::Error(String::from_utf8_lossy(&val).replace(['\r', '\n'], " ")),
+            Value::Verbatim(_, val) => Value::Data(val),
+            Value::Map(pairs) => {
+                let mut items = Vec::with_capacity(2 * pairs.len());
+                for (key, val) in pairs {
+                    items.push(key.into_resp2());
+                    items.push(val.into_resp2());
                 }
+                Value::Bulk(items)
+            }
+            Value::Bulk(items) | Value::Set(items) | Value::Push(items) => {
+                Value::Bulk(items.into_iter().map(Value::into_resp2).collect())
             }
+            Value::Attribute(_, val) => val.into_resp2(),
+            val => val,
         }
-
-        buf
-    }   //// encode()
+    }
 
     #[inline]
     pub fn to_string(&self) -> ::std::result::Result<Option<String>, ::std::str::Utf8Error> {
@@ -193,17 +278,24 @@ impl Value {
                     Err(e) => return Err(e),
                 }
             }
+            #[cfg(feature = "bytes")]
+            Value::Shared(ref val) => Some(str::from_utf8(val)?.to_owned()),
+            Value::Double(val) => Some(Double(val).to_string()),
+            Value::BigNumber(ref val) => Some(val.clone()),
+            Value::Verbatim(_, ref val) => Some(str::from_utf8(val)?.to_owned()),
             _ => None,
         };
 
         Ok(s)
     }   //// to_string()
 
-    #[inline]
     const NULL_SLICE: &'static [u8] = b"";
+    #[inline]
     pub fn as_slice(&self) -> &[u8] {
         match *self {
             Value::Data(ref val) => val.as_slice(),
+            #[cfg(feature = "bytes")]
+            Value::Shared(ref val) => val,
             _ => Self::NULL_SLICE,
         }
     }
@@ -223,8 +315,45 @@ pub fn encode_slice(slice: &[&str]) -> Vec<u8> {
     Value::Bulk(array).encode()
 }
 
-fn parse_length(bytes: &[u8]) -> Result<i64> {
-    String::from_utf8(bytes.to_vec()).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?.parse::<i64>().map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+/// Formats a double like RESP3 does
+struct Double(f64);
+
+impl fmt::Display for Double {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_nan() {
+            write!(fmt, "nan")
+        } else {
+            // "inf", "-inf" or the shortest decimal which parses back to the same double
+            write!(fmt, "{}", self.0)
+        }
+    }
+}
+
+/// Number of characters of `val` once displayed, without formatting it in a buffer.
+fn display_len<T: fmt::Display>(val: T) -> usize {
+    struct Counter(usize);
+
+    impl fmt::Write for Counter {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0 += s.len();
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    fmt::write(&mut counter, format_args!("{}", val)).expect("counting never fails");
+    counter.0
+}
+
+/// Number of characters of `n` in decimal.
+fn decimal_len(n: i64) -> usize {
+    let mut len = if n < 0 { 2 } else { 1 };
+    let mut n = n.unsigned_abs() / 10;
+    while n > 0 {
+        len += 1;
+        n /= 10;
+    }
+    len
 }
 
 impl fmt::Debug for Value {
@@ -239,14 +368,21 @@ impl fmt::Debug for Value {
                     Err(_) => write!(fmt, "binary-data({:?})", val),
                 }
             }
+            #[cfg(feature = "bytes")]
+            Value::Shared(ref val) => {
+                match str::from_utf8(val) {
+                    Ok(x) => write!(fmt, "shared-data('{:?}')", x),
+                    Err(_) => write!(fmt, "shared-binary-data({:?})", &val[..]),
+                }
+            }
             Value::Bulk(ref values) => {
-                try!(write!(fmt, "bulk("));
+                write!(fmt, "bulk(")?;
                 let mut is_first = true;
                 for val in values.iter() {
                     if !is_first {
-                        try!(write!(fmt, ", "));
+                        write!(fmt, ", ")?;
                     }
-                    try!(write!(fmt, "{:?}", val));
+                    write!(fmt, "{:?}", val)?;
                     is_first = false;
                 }
                 write!(fmt, ")")
@@ -254,6 +390,16 @@ impl fmt::Debug for Value {
             //Value::Okay => write!(fmt, "ok"),
             Value::Status(ref s) => write!(fmt, "status({:?})", s),
             Value::Error(ref s) => write!(fmt, "error({:?})", s),
+            Value::Null => write!(fmt, "null"),
+            Value::Double(val) => write!(fmt, "double({})", Double(val)),
+            Value::Boolean(val) => write!(fmt, "boolean({:?})", val),
+            Value::BigNumber(ref s) => write!(fmt, "big-number({})", s),
+            Value::BlobError(ref val) => write!(fmt, "blob-error({:?})", String::from_utf8_lossy(val)),
+            Value::Verbatim(ref format, ref val) => write!(fmt, "verbatim({}:{:?})", format, String::from_utf8_lossy(val)),
+            Value::Map(ref pairs) => write!(fmt, "map({:?})", pairs),
+            Value::Set(ref values) => write!(fmt, "set({:?})", values),
+            Value::Attribute(ref pairs, ref val) => write!(fmt, "attribute({:?}, {:?})", pairs, val),
+            Value::Push(ref values) => write!(fmt, "push({:?})", values),
         }
     }
 }
@@ -266,6 +412,7 @@ impl fmt::Debug for Value {
 #[cfg(test)]
 mod tests {
     use super::Value;
+    use super::super::Error;
 
     struct Case {
         data: Vec<u8>,
@@ -339,6 +486,52 @@ mod tests {
                 data: "*4\r\n:1\r\n:2\r\n:3\r\n$6\r\nfoobar\r\n".to_string().into_bytes(),
                 want: Value::Bulk(vec![Value::Int(1), Value::Int(2), Value::Int(3), Value::Data(b"foobar".to_vec())]),
             },
+            Case {
+                data: "_\r\n".to_string().into_bytes(),
+                want: Value::Null,
+            },
+            Case {
+                data: ",1.5\r\n".to_string().into_bytes(),
+                want: Value::Double(1.5),
+            },
+            Case {
+                data: ",-inf\r\n".to_string().into_bytes(),
+                want: Value::Double(f64::NEG_INFINITY),
+            },
+            Case {
+                data: "#t\r\n".to_string().into_bytes(),
+                want: Value::Boolean(true),
+            },
+            Case {
+                data: "(3492890328409238509324850943850943825024385\r\n".to_string().into_bytes(),
+                want: Value::BigNumber("3492890328409238509324850943850943825024385".to_string()),
+            },
+            Case {
+                data: "!21\r\nSYNTAX invalid syntax\r\n".to_string().into_bytes(),
+                want: Value::BlobError(b"SYNTAX invalid syntax".to_vec()),
+            },
+            Case {
+                data: "=15\r\ntxt:Some string\r\n".to_string().into_bytes(),
+                want: Value::Verbatim("txt".to_string(), b"Some string".to_vec()),
+            },
+            Case {
+                data: "%2\r\n+first\r\n:1\r\n+second\r\n~1\r\n#f\r\n".to_string().into_bytes(),
+                want: Value::Map(vec![
+                    (Value::Status("first".to_string()), Value::Int(1)),
+                    (Value::Status("second".to_string()), Value::Set(vec![Value::Boolean(false)])),
+                ]),
+            },
+            Case {
+                data: "|1\r\n+ttl\r\n:3600\r\n*2\r\n:1\r\n:2\r\n".to_string().into_bytes(),
+                want: Value::Attribute(
+                    vec![(Value::Status("ttl".to_string()), Value::Int(3600))],
+                    Box::new(Value::Bulk(vec![Value::Int(1), Value::Int(2)])),
+                ),
+            },
+            Case {
+                data: ">2\r\n+message\r\n$5\r\nhello\r\n".to_string().into_bytes(),
+                want: Value::Push(vec![Value::Status("message".to_string()), Value::Data(b"hello".to_vec())]),
+            },
         ];
 
         // single decode
@@ -347,6 +540,12 @@ mod tests {
             assert_eq!(val, case.want);
         }
 
+        // encode back
+        for case in cases {
+            assert_eq!(case.data, case.want.encode());
+            assert_eq!(case.data.len(), case.want.encoded_len());
+        }
+
         // multiple decode
         let mut all: Vec<u8> = Vec::new();
         for case in cases {
@@ -359,6 +558,145 @@ mod tests {
             offset = _offset;
         }
     }
-}
 
+    #[test]
+    fn decode_errors() {
+        let incomplete: &[&str] = &["", "+OK", "$6\r\nfoo", "*2\r\n$3\r\nfoo\r\n"];
+        for data in incomplete {
+            let (_, offset) = Value::decode(data.as_bytes(), 0).unwrap();
+            assert_eq!(0, offset, "{:?}", data);
+        }
+
+        let malformed = |data: &[u8]| Value::decode(data, 0).unwrap_err();
+
+        match malformed(b"GET a\r\n") {
+            Error::InvalidPrefix(b'G') => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        match malformed(b"+OK\n") {
+            Error::InvalidCrlf => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        match malformed(b"$3\r\nfoobar\r\n") {
+            Error::InvalidCrlf => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        match malformed(b"$x\r\n") {
+            Error::InvalidLength(b'$') => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        match malformed(b"*-2\r\n") {
+            Error::InvalidLength(b'*') => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        match malformed(b":1x\r\n") {
+            Error::InvalidInteger => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        match malformed(b"$536870913\r\n") {
+            Error::FrameTooLarge => {}
+            e => panic!("unexpected {:?}", e),
+        }
+        match malformed(b"+\xff\r\n") {
+            Error::Utf8(_) => {}
+            e => panic!("unexpected {:?}", e),
+        }
+
+        for data in &[&b"_x\r\n"[..], b"#x\r\n", b",1.5x\r\n", b"(12a\r\n", b"=3\r\ntxt\r\n"] {
+            match malformed(data) {
+                Error::InvalidValue(prefix) if prefix == data[0] => {}
+                e => panic!("unexpected {:?}", e),
+            }
+        }
+        match malformed(b"%-1\r\n") {
+            Error::InvalidLength(b'%') => {}
+            e => panic!("unexpected {:?}", e),
+        }
+
+        assert_eq!("invalid bulk length", Error::InvalidLength(b'$').to_string());
+        assert_eq!("invalid multibulk length", Error::InvalidLength(b'*').to_string());
+        assert!(Error::InvalidCrlf.is_protocol());
+    }
 
+    #[test]
+    fn encode_into_buffer() {
+        let value = Value::Bulk(vec![
+            Value::Nil,
+            Value::NullArray,
+            Value::Int(i64::MIN),
+            Value::Int(0),
+            Value::Int(-10),
+            Value::Status("OK".to_owned()),
+            Value::Error("ERR".to_owned()),
+            Value::Data(vec![b'x'; 100]),
+            Value::Bulk(vec![Value::Data(Vec::new()), Value::Bulk(Vec::new())]),
+        ]);
+        let want = b"*9\r\n$-1\r\n*-1\r\n:-9223372036854775808\r\n:0\r\n:-10\r\n+OK\r\n-ERR\r\n$100\r\n".iter()
+            .chain(&[b'x'; 100])
+            .chain(b"\r\n*2\r\n$0\r\n\r\n*0\r\n")
+            .cloned()
+            .collect::<Vec<u8>>();
+
+        let buf = value.encode();
+        assert_eq!(want, buf);
+        assert_eq!(want.len(), value.encoded_len());
+        assert_eq!(want.len(), buf.capacity());
+
+        let mut buf = b"+OK\r\n".to_vec();
+        value.encode_into(&mut buf);
+        assert_eq!(&want[..], &buf[5..]);
+
+        let mut buf = Vec::new();
+        value.write_to(&mut buf).unwrap();
+        assert_eq!(want, buf);
+        assert_eq!(Value::decode(&buf, 0).unwrap(), (value, want.len()));
+    }
+
+    #[test]
+    fn downgrade_to_resp2() {
+        let value = Value::Attribute(
+            vec![(Value::Status("ttl".to_owned()), Value::Int(3600))],
+            Box::new(Value::Map(vec![
+                (Value::Data(b"null".to_vec()), Value::Null),
+                (Value::Data(b"double".to_vec()), Value::Double(3.25)),
+                (Value::Data(b"nan".to_vec()), Value::Double(f64::NAN)),
+                (Value::Data(b"boolean".to_vec()), Value::Boolean(true)),
+                (Value::Data(b"big".to_vec()), Value::BigNumber("-12345678901234567890".to_owned())),
+                (Value::Data(b"verbatim".to_vec()), Value::Verbatim("txt".to_owned(), b"text".to_vec())),
+                (Value::Data(b"set".to_vec()), Value::Set(vec![Value::Push(vec![Value::Null])])),
+            ])),
+        );
+        let want = Value::Bulk(vec![
+            Value::Data(b"null".to_vec()), Value::Nil,
+            Value::Data(b"double".to_vec()), Value::Data(b"3.25".to_vec()),
+            Value::Data(b"nan".to_vec()), Value::Data(b"nan".to_vec()),
+            Value::Data(b"boolean".to_vec()), Value::Int(1),
+            Value::Data(b"big".to_vec()), Value::Data(b"-12345678901234567890".to_vec()),
+            Value::Data(b"verbatim".to_vec()), Value::Data(b"text".to_vec()),
+            Value::Data(b"set".to_vec()), Value::Bulk(vec![Value::Bulk(vec![Value::Nil])]),
+        ]);
+        assert_eq!(want, value.into_resp2());
+        assert_eq!(Value::Error("ERR bad\r\nline".replace("\r\n", "  ")), Value::BlobError(b"ERR bad\r\nline".to_vec()).into_resp2());
+    }
+
+    #[test]
+    fn compare_doubles_by_bits() {
+        assert_eq!(Value::Double(f64::NAN), Value::Double(f64::NAN));
+        assert_eq!(Value::Double(1.5), Value::Double(1.5));
+        assert!(Value::Double(0.0) != Value::Double(-0.0));
+        assert!(Value::Double(1.0) != Value::Int(1));
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn compare_shared_and_data_by_bytes() {
+        use bytes::Bytes;
+
+        let shared = Value::Shared(Bytes::from_static(b"GET"));
+        assert_eq!(Value::Data(b"GET".to_vec()), shared);
+        assert_eq!(shared, Value::Data(b"GET".to_vec()));
+        assert!(shared != Value::Data(b"SET".to_vec()));
+        assert!(shared != Value::Status("GET".to_owned()));
+        assert_eq!(Value::Bulk(vec![Value::Data(b"GET".to_vec())]), Value::Bulk(vec![shared]));
+    }
+}
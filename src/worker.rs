@@ -0,0 +1,296 @@
+//! Handler calls, on the event loop thread or on a pool of worker threads
+//!
+
+use std::collections::VecDeque;
+use std::io::Result;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use amy::Sender;
+use context::{ClientClass, ConnContext, DisconnectReason};
+use reply::{Reply, Token};
+use value::{Protocol, Value};
+use Handler;
+
+/// Work for the handler of a client
+#[derive(Debug)]
+pub enum Job {
+    Connect,
+    Request(Value),
+    Disconnect(DisconnectReason),
+}
+
+/// Reply to a request, encoded for the client
+#[derive(Debug)]
+pub enum Output {
+    Data(Vec<u8>),
+    Deferred(Token),
+}
+
+/// What a batch of jobs of a client came to
+#[derive(Debug)]
+pub struct Done {
+    pub client: usize,
+    /// number of `Job::Request` run
+    pub requests: usize,
+    pub outputs: Vec<Output>,
+    pub protocol: Protocol,
+    pub class: ClientClass,
+    pub killed: bool,
+    /// whether the handler panicked, the outputs of the batch are lost
+    pub panicked: bool,
+}
+
+/// The handler of a client, along with the client context
+pub struct Session {
+    ctx: ConnContext,
+    handler: Box<dyn Handler + Send>,
+}
+
+impl Session {
+    pub fn new(ctx: ConnContext, handler: Box<dyn Handler + Send>) -> Session {
+        Session { ctx, handler }
+    }
+
+    fn run(&mut self, job: Job, done: &mut Done) {
+        match job {
+            Job::Connect => self.handler.on_connect(&mut self.ctx),
+            Job::Request(req) => {
+                done.requests += 1;
+                // the requests after the one killing the connection are dropped
+                if self.ctx.killed {
+                    return;
+                }
+                self.ctx.last_active = Instant::now();
+                let reply = match command_args(&req, "HELLO") {
+                    Some(args) => Reply::Value(hello(args, &mut self.ctx, &mut *self.handler)),
+                    None => self.handler.handle_with_ctx(&mut self.ctx, &req),
+                };
+                match reply {
+                    Reply::Value(val) => done.outputs.push(Output::Data(encode_reply(val, self.ctx.protocol))),
+                    Reply::None => {}
+                    Reply::Deferred(token) => done.outputs.push(Output::Deferred(token)),
+                }
+            }
+            Job::Disconnect(reason) => {
+                if let DisconnectReason::Io(ref e) = reason {
+                    self.handler.on_error(&mut self.ctx, e);
+                }
+                self.handler.on_disconnect(&mut self.ctx, &reason);
+            }
+        }
+    }
+}
+
+/// Jobs of a client, run one after the other
+///
+/// Once its handler panics, the mailbox drops the jobs left and the ones posted after.
+pub struct Mailbox {
+    client: usize,
+    session: Mutex<Session>,
+    jobs: Mutex<Jobs>,
+    /// to the event loop of the client, for the jobs run by workers
+    finished: Arc<Sender<Done>>,
+}
+
+struct Jobs {
+    queue: VecDeque<Job>,
+    /// whether a thread runs the jobs, or is about to
+    scheduled: bool,
+    panicked: bool,
+}
+
+impl Mailbox {
+    pub fn new(session: Session, finished: Arc<Sender<Done>>) -> Arc<Mailbox> {
+        Arc::new(Mailbox {
+            client: session.ctx.id(),
+            session: Mutex::new(session),
+            jobs: Mutex::new(Jobs { queue: VecDeque::new(), scheduled: false, panicked: false }),
+            finished,
+        })
+    }
+
+    /// Queue a job, and return true if the mailbox has to be scheduled to run it.
+    pub fn post(&self, job: Job) -> bool {
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.panicked {
+            return false;
+        }
+        jobs.queue.push_back(job);
+        !std::mem::replace(&mut jobs.scheduled, true)
+    }
+
+    /// Run the jobs queued until there is none left, and `report()` each batch of them.
+    pub fn run<F: FnMut(Done)>(&self, mut report: F) {
+        loop {
+            let batch: Vec<Job> = {
+                let mut jobs = self.jobs.lock().unwrap();
+                if jobs.queue.is_empty() {
+                    jobs.scheduled = false;
+                    return;
+                }
+                jobs.queue.drain(..).collect()
+            };
+
+            let requests = batch.iter().filter(|job| matches!(job, Job::Request(_))).count();
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                let mut session = self.session.lock().unwrap();
+                let mut done = Done {
+                    client: self.client,
+                    requests: 0,
+                    outputs: Vec::new(),
+                    protocol: session.ctx.protocol,
+                    class: session.ctx.class,
+                    killed: false,
+                    panicked: false,
+                };
+                for job in batch {
+                    session.run(job, &mut done);
+                }
+                done.protocol = session.ctx.protocol;
+                done.class = session.ctx.class;
+                done.killed = session.ctx.killed;
+                done
+            }));
+            match res {
+                Ok(done) => report(done),
+                Err(_) => {
+                    // the session is poisoned, the client is disconnected
+                    {
+                        let mut jobs = self.jobs.lock().unwrap();
+                        jobs.queue.clear();
+                        jobs.scheduled = false;
+                        jobs.panicked = true;
+                    }
+                    report(Done {
+                        client: self.client,
+                        requests,
+                        outputs: Vec::new(),
+                        protocol: Protocol::default(),
+                        class: ClientClass::default(),
+                        killed: false,
+                        panicked: true,
+                    });
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Threads running the jobs of the mailboxes submitted to their `WorkQueue`
+pub struct Workers {
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Submits mailboxes to the workers, which stop once every queue is dropped
+#[derive(Clone)]
+pub struct WorkQueue {
+    tx: mpsc::Sender<Arc<Mailbox>>,
+}
+
+impl Workers {
+    /// Spawn `count` threads.
+    pub fn start(count: usize) -> Result<(Workers, WorkQueue)> {
+        let (tx, rx) = mpsc::channel::<Arc<Mailbox>>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        let mut threads = Vec::with_capacity(count);
+        for i in 0..count {
+            let rx = rx.clone();
+            threads.push(thread::Builder::new().name(format!("redif-worker-{}", i)).spawn(move || {
+                loop {
+                    let mailbox = rx.lock().unwrap().recv();
+                    match mailbox {
+                        // the event loop may be gone on shutdown
+                        Ok(mailbox) => mailbox.run(|batch| { let _ = mailbox.finished.send(batch); }),
+                        Err(_) => return,
+                    }
+                }
+            })?);
+        }
+
+        Ok((Workers { threads }, WorkQueue { tx }))
+    }
+
+    /// Wait until the threads ran the jobs submitted, and stopped.
+    pub fn join(self) {
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl WorkQueue {
+    pub fn submit(&self, mailbox: Arc<Mailbox>) {
+        let _ = self.tx.send(mailbox);
+    }
+}
+
+/// Encode a reply in the protocol version of the client.
+pub fn encode_reply(val: Value, protocol: Protocol) -> Vec<u8> {
+    match protocol {
+        Protocol::Resp2 => val.into_resp2().encode(),
+        Protocol::Resp3 => val.encode(),
+    }
+}
+
+/// The arguments of a request for command `name`, the name included.
+fn command_args<'a>(req: &'a Value, name: &str) -> Option<&'a [Value]> {
+    match *req {
+        Value::Bulk(ref args) if !args.is_empty() && args[0].as_slice().eq_ignore_ascii_case(name.as_bytes()) => Some(args),
+        _ => None,
+    }
+}
+
+/// Switch to the protocol version asked like Redis
+/// `HELLO [protover [AUTH username password] [SETNAME clientname]]` does, and describe the server.
+///
+/// The credentials are checked by `Handler::auth()`.
+fn hello(args: &[Value], ctx: &mut ConnContext, handler: &mut dyn Handler) -> Value {
+    let protocol = match args.get(1).map(|version| version.as_slice()) {
+        None => ctx.protocol,
+        Some(b"2") => Protocol::Resp2,
+        Some(b"3") => Protocol::Resp3,
+        Some(_) => return Value::Error("NOPROTO unsupported protocol version".to_owned()),
+    };
+
+    let (mut auth, mut name) = (None, None);
+    let mut i = 2;
+    while i < args.len() {
+        let option = args[i].as_slice();
+        if option.eq_ignore_ascii_case(b"AUTH") && i + 2 < args.len() {
+            auth = Some((args[i + 1].as_slice(), args[i + 2].as_slice()));
+            i += 3;
+        } else if option.eq_ignore_ascii_case(b"SETNAME") && i + 1 < args.len() {
+            name = Some(String::from_utf8_lossy(args[i + 1].as_slice()).into_owned());
+            i += 2;
+        } else {
+            return Value::Error(format!("ERR Syntax error in HELLO option '{}'", String::from_utf8_lossy(option)));
+        }
+    }
+
+    if let Some((username, password)) = auth {
+        if let Err(e) = handler.auth(ctx, username, password) {
+            return e;
+        }
+    }
+    if name.is_some() {
+        ctx.set_name(name);
+    }
+    ctx.protocol = protocol;
+
+    let field = |name: &str, val: Value| (Value::Data(name.as_bytes().to_vec()), val);
+    let text = |s: &str| Value::Data(s.as_bytes().to_vec());
+    Value::Map(vec![
+        field("server", text("redif")),
+        field("version", text(env!("CARGO_PKG_VERSION"))),
+        field("proto", Value::Int(if protocol == Protocol::Resp3 { 3 } else { 2 })),
+        field("id", Value::Int(ctx.id() as i64)),
+        field("mode", text("standalone")),
+        field("role", text("master")),
+        field("modules", Value::Bulk(Vec::new())),
+    ])
+}